@@ -1,85 +1,1462 @@
 use jack;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
 use rustfft::algorithm::{Radix4};
 use rustfft::FFT;
 use rustfft::num_complex::Complex;
 use rustfft::num_traits::Zero;
 
-pub struct ActiveProducer {
+// most samples queued between a source and its consumer, about a second at the 
+// default sample size whatever the hop, since every one is a preallocated buffer
+const MAX_DEPTH: usize = 48;
+
+pub struct ActiveProducer<S: InactiveSource> {
     sample_size: usize,
+    hop: usize,
     recv: mpsc::Receiver<StereoSample>,
-    source: ActiveSource
+    dropped: Arc<AtomicUsize>,
+    source: S::ActiveType
 }
 
 #[derive(Debug)]
-pub struct PendingProducer {
+pub struct PendingProducer<S: InactiveSource> {
     sample_size: usize,
+    hop: usize,
+    depth: usize,
     sender: mpsc::SyncSender<StereoSample>,
     recv: mpsc::Receiver<StereoSample>,
-    source: InactiveSource
+    source: S
 }
 
-#[derive(Debug)]
-enum InactiveSource {
-   Jack(jack::Client)
-}
-enum ActiveSource {
-    Jack(jack::AsyncClient<Notifications, FrameHandler>)
+/// A backend that can be started to deliver audio into a `PendingProducer`.
+/// Once activated it pushes whatever it reads into `framer` until it runs out 
+/// of input or `Framer::push` reports the consumer is gone, then drops the framer.
+pub trait InactiveSource {
+    type ActiveType: Send + 'static;
+    fn sample_rate(&self) -> usize;
+    fn activate(self, framer: Framer) -> Result<Self::ActiveType, SpectrumError>;
 }
 
-impl PendingProducer {
+impl<S: InactiveSource> PendingProducer<S> {
+    pub fn new(source: S, sample_size: usize) -> Self {
+        let depth = (source.sample_rate() / sample_size).clamp(1, MAX_DEPTH);
+        let (sender, recv) = mpsc::sync_channel(depth);
+        PendingProducer { sample_size, hop: sample_size, depth, sender, recv, source }
+    }
+    /// Emit a new window every `hop` frames instead of every `sample_size` frames,
+    /// so consecutive `StereoSample`s overlap by `sample_size - hop` frames. Hops 
+    /// shorter than an eighth of the sample only add load.
+    pub fn set_hop(mut self, hop: usize) -> Self {
+        if hop * 8 < self.sample_size || hop > self.sample_size {
+            panic!("hop must be in [sample_size / 8, sample_size]");
+        }
+        self.hop = hop;
+        self
+    }
+    pub fn sample_rate(&self) -> usize {
+        self.source.sample_rate()
+    }
+    pub fn activate(self) -> Result<ActiveProducer<S>, SpectrumError>  {
+        let framer = Framer::new(self.sample_size, self.hop, self.depth, self.sender);
+        let dropped = framer.dropped.clone();
+        let source = self.source.activate(framer)?;
+        Ok(ActiveProducer {
+            sample_size: self.sample_size,
+            hop: self.hop,
+            recv: self.recv,
+            dropped,
+            source
+        })
+    }
+}
+impl PendingProducer<jack::Client> {
     pub fn new_jack(sample_size: usize) -> Result<Self, SpectrumError> {
         let (client, _status) = match jack::Client::new("spectrum", jack::ClientOptions::NO_START_SERVER) {
             Ok(ok) => ok,
             Err(err) => return Err(SpectrumError::JackError(err))
         };
-        let (sender, recv) = mpsc::sync_channel(client.sample_rate() / sample_size);
-        Ok(PendingProducer {
-            sample_size: sample_size,
-            sender: sender,
-            recv: recv,
-            source: InactiveSource::Jack(client),
+        Ok(PendingProducer::new(client, sample_size))
+    }
+}
+impl PendingProducer<WavFile> {
+    pub fn new_wav<P: AsRef<Path>>(path: P, sample_size: usize, realtime: bool) -> Result<Self, SpectrumError> {
+        Ok(PendingProducer::new(WavFile::open(path, realtime)?, sample_size))
+    }
+}
+
+pub struct JackActive {
+    _client: jack::AsyncClient<Notifications, FrameHandler> // held so capture runs until drop
+}
+
+impl InactiveSource for jack::Client {
+    type ActiveType = JackActive;
+    fn sample_rate(&self) -> usize {
+        jack::Client::sample_rate(self)
+    }
+    fn activate(self, mut framer: Framer) -> Result<Self::ActiveType, SpectrumError> {
+        framer.set_blocking(false); // the process callback must never wait on the consumer
+        let in_l = match self.register_port("left", jack::AudioIn::default()) {
+            Ok(ok) => ok,
+            Err(err) => return Err(SpectrumError::JackError(err))
+        };
+        let in_r = match self.register_port("right", jack::AudioIn::default()) {
+            Ok(ok) => ok,
+            Err(err) => return Err(SpectrumError::JackError(err))
+        };
+        let names = [in_l.name(), in_r.name()];
+        let handler = FrameHandler {
+            framer,
+            left: in_l,
+            right: in_r
+        };
+        let active = match self.activate_async(Notifications, handler) {
+            Ok(active) => active,
+            Err(err) => return Err(SpectrumError::JackError(err))
+        };
+        // listen to the physical capture ports, if there are any
+        let capture = active.as_client().ports(None, Some("audio"), jack::PortFlags::IS_OUTPUT | jack::PortFlags::IS_PHYSICAL);
+        if capture.is_empty() {
+            eprintln!("no capture ports to connect the input to");
+        }
+        for (src, name) in capture.iter().zip(names.iter()) {
+            let res = match name {
+                Ok(name) => active.as_client().connect_ports_by_name(src, name),
+                Err(err) => Err(err.clone())
+            };
+            if let Err(err) = res {
+                eprintln!("unable to connect to {}: {:?}", src, err);
+            }
+        }
+        Ok(JackActive { _client: active })
+    }
+}
+
+/// Collects audio from a source into a ring buffer of `sample_size` frames and
+/// sends a copy of the most recent `sample_size` frames every `hop` frames.
+/// Sent samples find their way back to the framer's pool when dropped, so once 
+/// the pool is warm no buffers are allocated.
+pub struct Framer {
+    sample_size: usize,
+    hop: usize,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    pos: usize, // next write position in the ring
+    filled: usize, // frames in the ring, up to sample_size
+    since: usize, // frames written since the last window was sent
+    sender: mpsc::SyncSender<StereoSample>,
+    free: Mutex<mpsc::Receiver<StereoSample>>, // only for Sync, always reached through get_mut()
+    give_back: mpsc::SyncSender<StereoSample>,
+    spare: Option<StereoSample>, // a filled buffer the consumer had no room for
+    blocking: bool,
+    dropped: Arc<AtomicUsize>
+}
+impl Framer {
+    /// `depth` is the capacity of `sender`; enough buffers are allocated up front
+    /// to fill it while the consumer holds on to one more.
+    pub fn new(sample_size: usize, hop: usize, depth: usize, sender: mpsc::SyncSender<StereoSample>) -> Self {
+        let buffers = depth + 2;
+        let (give_back, free) = mpsc::sync_channel(buffers);
+        for _ in 0..buffers {
+            give_back.send(StereoSample::new(sample_size)).unwrap(); // can't fail, we hold the receiver
+        }
+        Framer {
+            sample_size, hop, sender, give_back,
+            free: Mutex::new(free),
+            left: vec![0.0; sample_size],
+            right: vec![0.0; sample_size],
+            pos: 0,
+            filled: 0,
+            since: 0,
+            spare: None,
+            blocking: true,
+            dropped: Arc::new(AtomicUsize::new(0))
+        }
+    }
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+    /// When blocking (the default) `push` waits for the consumer to make room,
+    /// otherwise windows that don't fit are dropped and counted. Real-time 
+    /// sources should not block.
+    pub fn set_blocking(&mut self, blocking: bool) {
+        self.blocking = blocking;
+    }
+    /// Returns false once the consumer has gone away.
+    pub fn push(&mut self, mut left: &[f32], mut right: &[f32]) -> bool {
+        if left.len() != right.len() {
+            panic!("Error mismatched size");
+        }
+        while !left.is_empty() {
+            let needed = if self.filled < self.sample_size { 
+                self.sample_size - self.filled 
+            } else { 
+                self.hop - self.since 
+            };
+            let n = needed.min(left.len()).min(self.sample_size - self.pos);
+            self.left[self.pos..self.pos + n].copy_from_slice(&left[..n]);
+            self.right[self.pos..self.pos + n].copy_from_slice(&right[..n]);
+            self.pos = (self.pos + n) % self.sample_size;
+            self.filled = (self.filled + n).min(self.sample_size);
+            self.since += n;
+            left = &left[n..];
+            right = &right[n..];
+            if self.filled == self.sample_size && self.since >= self.hop {
+                self.since = 0;
+                if !self.emit() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    fn emit(&mut self) -> bool {
+        let mut ss = match self.spare.take() {
+            Some(ss) => ss,
+            None => match self.free.get_mut().unwrap().try_recv() {
+                Ok(ss) => ss,
+                Err(_) if self.blocking => StereoSample::new(self.sample_size),
+                Err(_) => { // the consumer is sitting on every buffer
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+            }
+        };
+        // unroll the ring so the oldest frame comes first
+        ss.left.clear();
+        ss.right.clear();
+        ss.extend(&self.left[self.pos..], &self.right[self.pos..]);
+        ss.extend(&self.left[..self.pos], &self.right[..self.pos]);
+        ss.pool = Some(self.give_back.clone());
+        if self.blocking {
+            return self.sender.send(ss).is_ok();
+        }
+        match self.sender.try_send(ss) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(mut ss)) => {
+                ss.pool = None;
+                self.spare = Some(ss);
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            Err(mpsc::TrySendError::Disconnected(_)) => false
+        }
+    }
+}
+
+/// Handle to a backend running on its own thread (files, pipes, generators).
+/// The thread exits on its own once its input is exhausted or the consumer hangs up.
+pub struct ThreadActive {
+    _handle: thread::JoinHandle<()>
+}
+
+// Sleeps so that blocks leave at the rate they would arrive from a sound card.
+struct Pacer {
+    start: Instant,
+    frames: u64,
+    sample_rate: u64
+}
+impl Pacer {
+    fn new(sample_rate: usize) -> Self {
+        Pacer { start: Instant::now(), frames: 0, sample_rate: sample_rate as u64 }
+    }
+    fn wait(&mut self, frames: usize) {
+        self.frames += frames as u64;
+        let due = self.start + Duration::from_micros(self.frames * 1_000_000 / self.sample_rate);
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Encodings of little-endian interleaved PCM that the file and stream backends accept.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum SampleFormat {
+    U8,
+    S16,
+    S24,
+    S32,
+    F32,
+    F64
+}
+impl FromStr for SampleFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "u8" => Ok(SampleFormat::U8),
+            "s16" | "s16le" => Ok(SampleFormat::S16),
+            "s24" | "s24le" => Ok(SampleFormat::S24),
+            "s32" | "s32le" => Ok(SampleFormat::S32),
+            "f32" | "f32le" | "float32le" => Ok(SampleFormat::F32),
+            "f64" | "f64le" | "float64le" => Ok(SampleFormat::F64),
+            _ => Err(format!("unknown sample format {:?}", s))
+        }
+    }
+}
+impl SampleFormat {
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::S32 | SampleFormat::F32 => 4,
+            SampleFormat::F64 => 8
+        }
+    }
+    // converts one sample to the [-1, 1] range JACK uses
+    fn decode(self, b: &[u8]) -> f32 {
+        match self {
+            SampleFormat::U8 => (b[0] as f32 - 128.0) / 128.0,
+            SampleFormat::S16 => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+            SampleFormat::S24 => (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0,
+            SampleFormat::S32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+            SampleFormat::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            SampleFormat::F64 => {
+                let mut a = [0; 8];
+                a.copy_from_slice(&b[0..8]);
+                f64::from_le_bytes(a) as f32
+            }
+        }
+    }
+}
+
+// Turns a byte stream of interleaved frames into StereoSamples. Mono input is 
+// copied to both channels and anything past the second channel is ignored.
+struct PcmReader<R: Read> {
+    reader: R,
+    format: SampleFormat,
+    channels: usize,
+    remaining: Option<u64>, // bytes left, if the stream has a known length
+    buf: Vec<u8>
+}
+impl<R: Read> PcmReader<R> {
+    fn new(reader: R, format: SampleFormat, channels: usize, remaining: Option<u64>) -> Self {
+        PcmReader { reader, format, channels, remaining, buf: Vec::new() }
+    }
+    fn frame_len(&self) -> usize {
+        self.format.bytes() * self.channels
+    }
+    // Reads `frames` frames into left and right, returns false once the stream cannot fill them.
+    fn read_frames(&mut self, frames: usize, left: &mut Vec<f32>, right: &mut Vec<f32>) -> io::Result<bool> {
+        let frame_len = self.frame_len();
+        let want = frames * frame_len;
+        if let Some(rem) = self.remaining {
+            if rem < want as u64 {
+                return Ok(false);
+            }
+        }
+        self.buf.resize(want, 0);
+        let mut filled = 0;
+        while filled < want {
+            match self.reader.read(&mut self.buf[filled..]) {
+                Ok(0) => return Ok(false),
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+        if let Some(rem) = self.remaining.as_mut() {
+            *rem -= want as u64;
+        }
+        let size = self.format.bytes();
+        let right_off = if self.channels > 1 { size } else { 0 };
+        left.clear();
+        right.clear();
+        for frame in self.buf.chunks(frame_len) {
+            left.push(self.format.decode(&frame[0..size]));
+            right.push(self.format.decode(&frame[right_off..right_off + size]));
+        }
+        Ok(true)
+    }
+}
+
+// Drives a PcmReader on the current thread until input or consumer goes away.
+fn pump<R: Read>(mut pcm: PcmReader<R>, sample_rate: usize, realtime: bool, mut framer: Framer) {
+    let mut pacer = Pacer::new(sample_rate);
+    let chunk = framer.hop();
+    let mut left = Vec::with_capacity(chunk);
+    let mut right = Vec::with_capacity(chunk);
+    loop {
+        match pcm.read_frames(chunk, &mut left, &mut right) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(err) => {
+                eprintln!("error reading audio: {}", err);
+                break;
+            }
+        }
+        if realtime {
+            pacer.wait(chunk);
+        }
+        if !framer.push(&left, &right) {
+            break;
+        }
+    }
+}
+
+/// Plays back a RIFF/WAVE file. With `realtime` set blocks are released at 
+/// the file's sample rate, otherwise as fast as the consumer takes them.
+pub struct WavFile {
+    pcm: PcmReader<BufReader<File>>,
+    sample_rate: usize,
+    realtime: bool
+}
+impl WavFile {
+    pub fn open<P: AsRef<Path>>(path: P, realtime: bool) -> Result<Self, SpectrumError> {
+        let mut reader = match File::open(path) {
+            Ok(f) => BufReader::new(f),
+            Err(err) => return Err(SpectrumError::IoError(err))
+        };
+        let header = read_wav_header(&mut reader)?;
+        Ok(WavFile {
+            pcm: PcmReader::new(reader, header.format, header.channels, header.data_len),
+            sample_rate: header.sample_rate,
+            realtime
         })
     }
-    pub fn activate(self) -> Result<ActiveProducer, SpectrumError>  {
-        match self.source {
-            InactiveSource::Jack(s) => {
-                let in_l = match s.register_port("left", jack::AudioIn::default()) {
-                    Ok(ok) => ok,
-                    Err(err) => return Err(SpectrumError::JackError(err))
-                };
-                let in_r = match s.register_port("right", jack::AudioIn::default()) {
-                    Ok(ok) => ok,
-                    Err(err) => return Err(SpectrumError::JackError(err))
+    pub fn channels(&self) -> usize {
+        self.pcm.channels
+    }
+}
+impl InactiveSource for WavFile {
+    type ActiveType = ThreadActive;
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+    fn activate(self, framer: Framer) -> Result<Self::ActiveType, SpectrumError> {
+        let (pcm, rate, realtime) = (self.pcm, self.sample_rate, self.realtime);
+        let handle = thread::spawn(move || pump(pcm, rate, realtime, framer));
+        Ok(ThreadActive { _handle: handle })
+    }
+}
+
+/// Reads headerless interleaved PCM from stdin or a file/named pipe, e.g. the 
+/// output of `parec`, `arecord`, `ffmpeg` or `sox`. The writer sets the pace.
+pub struct PcmStream {
+    pcm: PcmReader<Box<dyn Read + Send>>,
+    sample_rate: usize
+}
+impl PcmStream {
+    pub fn stdin(format: SampleFormat, channels: usize, sample_rate: usize) -> Self {
+        PcmStream::new(Box::new(io::stdin()), format, channels, sample_rate)
+    }
+    pub fn open<P: AsRef<Path>>(path: P, format: SampleFormat, channels: usize, sample_rate: usize) 
+        -> Result<Self, SpectrumError> 
+    {
+        match File::open(path) {
+            Ok(f) => Ok(PcmStream::new(Box::new(f), format, channels, sample_rate)),
+            Err(err) => Err(SpectrumError::IoError(err))
+        }
+    }
+    pub fn new(reader: Box<dyn Read + Send>, format: SampleFormat, channels: usize, sample_rate: usize) -> Self {
+        if channels == 0 || sample_rate == 0 {
+            panic!("channels and sample_rate must be non-zero");
+        }
+        PcmStream { pcm: PcmReader::new(reader, format, channels, None), sample_rate }
+    }
+}
+impl InactiveSource for PcmStream {
+    type ActiveType = ThreadActive;
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+    fn activate(self, framer: Framer) -> Result<Self::ActiveType, SpectrumError> {
+        let (pcm, rate) = (self.pcm, self.sample_rate);
+        let handle = thread::spawn(move || pump(pcm, rate, false, framer));
+        Ok(ThreadActive { _handle: handle })
+    }
+}
+
+#[derive(Debug,PartialEq)]
+struct WavHeader {
+    format: SampleFormat,
+    channels: usize,
+    sample_rate: usize,
+    data_len: Option<u64>
+}
+
+fn read_u16<R: Read>(r: &mut R) -> Result<u16, SpectrumError> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b).map_err(SpectrumError::IoError)?;
+    Ok(u16::from_le_bytes(b))
+}
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, SpectrumError> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b).map_err(SpectrumError::IoError)?;
+    Ok(u32::from_le_bytes(b))
+}
+fn skip<R: Read>(r: &mut R, len: u64) -> Result<(), SpectrumError> {
+    match io::copy(&mut r.take(len), &mut io::sink()) {
+        Ok(n) if n == len => Ok(()),
+        Ok(_) => Err(SpectrumError::SpectrumError("wav file is truncated")),
+        Err(err) => Err(SpectrumError::IoError(err))
+    }
+}
+
+// Reads chunks up to the start of the sample data, leaving `r` positioned on it.
+fn read_wav_header<R: Read>(r: &mut R) -> Result<WavHeader, SpectrumError> {
+    let mut id = [0; 4];
+    r.read_exact(&mut id).map_err(SpectrumError::IoError)?;
+    if &id != b"RIFF" {
+        return Err(SpectrumError::SpectrumError("not a RIFF file"));
+    }
+    read_u32(r)?;
+    r.read_exact(&mut id).map_err(SpectrumError::IoError)?;
+    if &id != b"WAVE" {
+        return Err(SpectrumError::SpectrumError("not a WAVE file"));
+    }
+    let mut fmt = None;
+    loop {
+        r.read_exact(&mut id).map_err(SpectrumError::IoError)?;
+        let len = read_u32(r)?;
+        match &id {
+            b"fmt " => {
+                if len < 16 {
+                    return Err(SpectrumError::SpectrumError("wav fmt chunk is too short"));
+                }
+                let mut tag = read_u16(r)?;
+                let channels = read_u16(r)? as usize;
+                let sample_rate = read_u32(r)? as usize;
+                read_u32(r)?; // byte rate
+                read_u16(r)?; // block align
+                let bits = read_u16(r)?;
+                let mut used = 16;
+                if tag == 0xFFFE && len >= 40 { // WAVE_FORMAT_EXTENSIBLE, real tag leads the sub-format GUID
+                    read_u16(r)?; // extension size
+                    read_u16(r)?; // valid bits
+                    read_u32(r)?; // channel mask
+                    tag = read_u16(r)?;
+                    used = 26;
+                }
+                skip(r, (len - used) as u64 + (len & 1) as u64)?;
+                let format = match (tag, bits) {
+                    (1, 8) => SampleFormat::U8,
+                    (1, 16) => SampleFormat::S16,
+                    (1, 24) => SampleFormat::S24,
+                    (1, 32) => SampleFormat::S32,
+                    (3, 32) => SampleFormat::F32,
+                    (3, 64) => SampleFormat::F64,
+                    _ => return Err(SpectrumError::SpectrumError("unsupported wav sample format"))
                 };
-                let handler = FrameHandler {
-                    sample: StereoSample::new(self.sample_size),
-                    sender: self.sender,
-                    left: in_l,
-                    right: in_r,
-                    sample_size: self.sample_size
+                if channels == 0 || sample_rate == 0 {
+                    return Err(SpectrumError::SpectrumError("wav file has no channels or sample rate"));
+                }
+                fmt = Some((format, channels, sample_rate));
+            },
+            b"data" => {
+                let (format, channels, sample_rate) = match fmt {
+                    Some(f) => f,
+                    None => return Err(SpectrumError::SpectrumError("wav data chunk before fmt chunk"))
                 };
-                match s.activate_async(Notifications, handler) {
-                    Ok(active) => {
-                        Ok(ActiveProducer {
-                            sample_size: self.sample_size,
-                            recv: self.recv,
-                            source: ActiveSource::Jack(active)
-                        })
-                    },
-                    Err(err) => Err(SpectrumError::JackError(err))
+                // streaming writers leave the length as 0 or 0xFFFFFFFF
+                let data_len = if len == 0 || len == 0xFFFF_FFFF { None } else { Some(len as u64) };
+                return Ok(WavHeader { format, channels, sample_rate, data_len });
+            },
+            _ => skip(r, len as u64 + (len & 1) as u64)?
+        }
+    }
+}
+
+/// Deterministic test signals for checking the analysis without music or hardware.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Signal {
+    Sine { freq: f32, amp: f32 },
+    // logarithmic sweep from `start` to `end` Hz, restarting every `secs`
+    Sweep { start: f32, end: f32, secs: f32, amp: f32 },
+    WhiteNoise { amp: f32 },
+    PinkNoise { amp: f32 },
+    // a single full-scale sample every `secs`
+    Impulse { secs: f32, amp: f32 },
+    Silence
+}
+impl FromStr for Signal {
+    type Err = String;
+    /// Parses `sine:FREQ`, `sweep:START:END:SECS`, `white`, `pink`, `impulse:SECS` 
+    /// or `silence`, each optionally followed by `:AMP` (default 0.5).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let kind = parts.next().unwrap_or("");
+        let mut nums = Vec::new();
+        for p in parts {
+            match f32::from_str(p) {
+                Ok(f) if f.is_finite() => nums.push(f),
+                _ => return Err(format!("{:?} is not a number", p))
+            }
+        }
+        let (needed, name) = match kind {
+            "sine" => (1, "sine:FREQ[:AMP]"),
+            "sweep" => (3, "sweep:START:END:SECS[:AMP]"),
+            "white" => (0, "white[:AMP]"),
+            "pink" => (0, "pink[:AMP]"),
+            "impulse" => (1, "impulse:SECS[:AMP]"),
+            "silence" => (0, "silence"),
+            _ => return Err(format!("unknown signal {:?}", kind))
+        };
+        let max = if kind == "silence" { 0 } else { needed + 1 };
+        if nums.len() < needed || nums.len() > max {
+            return Err(format!("expected {}", name));
+        }
+        let amp = if nums.len() > needed { nums[needed] } else { 0.5 };
+        let sig = match kind {
+            "sine" => Signal::Sine { freq: nums[0], amp },
+            "sweep" => Signal::Sweep { start: nums[0], end: nums[1], secs: nums[2], amp },
+            "white" => Signal::WhiteNoise { amp },
+            "pink" => Signal::PinkNoise { amp },
+            "impulse" => Signal::Impulse { secs: nums[0], amp },
+            _ => Signal::Silence
+        };
+        match sig {
+            Signal::Sweep { start, end, secs, .. } if start <= 0.0 || end <= 0.0 || secs <= 0.0 =>
+                Err("sweep frequencies and period must be positive".to_string()),
+            Signal::Impulse { secs, .. } if secs <= 0.0 => Err("impulse period must be positive".to_string()),
+            _ => Ok(sig)
+        }
+    }
+}
+
+// Per-channel generator state for a Signal.
+struct Oscillator {
+    signal: Signal,
+    sample_rate: f32,
+    phase: f32,
+    frame: u64,
+    rng: u32,
+    pink: [f32; 7]
+}
+impl Oscillator {
+    fn new(signal: Signal, sample_rate: usize, seed: u32) -> Self {
+        Oscillator { 
+            signal, sample_rate: sample_rate as f32, phase: 0.0, frame: 0, 
+            rng: seed | 1, pink: [0.0; 7] 
+        }
+    }
+    // xorshift32 mapped to [-1, 1), so that runs are reproducible
+    fn white(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / 2147483648.0) - 1.0
+    }
+    fn advance(&mut self, freq: f32) -> f32 {
+        let ret = (self.phase * 2.0 * std::f32::consts::PI).sin();
+        self.phase = (self.phase + freq / self.sample_rate).fract();
+        ret
+    }
+    fn next(&mut self) -> f32 {
+        let t = self.frame;
+        self.frame += 1;
+        match self.signal {
+            Signal::Sine { freq, amp } => self.advance(freq) * amp,
+            Signal::Sweep { start, end, secs, amp } => {
+                let period = (secs * self.sample_rate) as u64;
+                let pos = (t % period.max(1)) as f32 / period.max(1) as f32;
+                if pos == 0.0 { self.phase = 0.0; }
+                self.advance(start * (end / start).powf(pos)) * amp
+            },
+            Signal::WhiteNoise { amp } => self.white() * amp,
+            Signal::PinkNoise { amp } => {
+                // Paul Kellet's refined pink filter, ~0.05dB ripple above 9Hz
+                let w = self.white();
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + w * 0.0555179;
+                b[1] = 0.99332 * b[1] + w * 0.0750759;
+                b[2] = 0.96900 * b[2] + w * 0.153852;
+                b[3] = 0.86650 * b[3] + w * 0.3104856;
+                b[4] = 0.55000 * b[4] + w * 0.5329522;
+                b[5] = -0.7616 * b[5] - w * 0.0168980;
+                let out = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + w * 0.5362;
+                b[6] = w * 0.115926;
+                out * 0.11 * amp
+            },
+            Signal::Impulse { secs, amp } => {
+                let period = ((secs * self.sample_rate) as u64).max(1);
+                if t.is_multiple_of(period) { amp } else { 0.0 }
+            },
+            Signal::Silence => 0.0
+        }
+    }
+}
+
+/// Generates a `Signal` on each channel. With `realtime` set blocks leave at
+/// `sample_rate`, otherwise as fast as the consumer takes them.
+pub struct SignalGenerator {
+    left: Signal,
+    right: Signal,
+    sample_rate: usize,
+    realtime: bool,
+    frames: Option<u64>
+}
+impl SignalGenerator {
+    pub fn new(left: Signal, right: Signal, sample_rate: usize, realtime: bool) -> Self {
+        SignalGenerator { left, right, sample_rate, realtime, frames: None }
+    }
+    /// Stop after roughly `secs` seconds of audio instead of running forever.
+    pub fn set_duration(mut self, secs: f32) -> Self {
+        self.frames = Some((secs * self.sample_rate as f32) as u64);
+        self
+    }
+    // the generator loop, shared by activate() and tests
+    fn run(&self, mut framer: Framer) {
+        let mut l_osc = Oscillator::new(self.left, self.sample_rate, 0x1234_5678);
+        let mut r_osc = Oscillator::new(self.right, self.sample_rate, 0x8765_4321);
+        let mut pacer = Pacer::new(self.sample_rate);
+        let chunk = framer.hop();
+        let mut left = vec![0.0; chunk];
+        let mut right = vec![0.0; chunk];
+        let mut sent = 0;
+        loop {
+            if let Some(f) = self.frames {
+                if sent + chunk as u64 > f { break; }
+            }
+            for i in 0..chunk {
+                left[i] = l_osc.next();
+                right[i] = r_osc.next();
+            }
+            if self.realtime {
+                pacer.wait(chunk);
+            }
+            if !framer.push(&left, &right) {
+                break;
+            }
+            sent += chunk as u64;
+        }
+    }
+}
+impl InactiveSource for SignalGenerator {
+    type ActiveType = ThreadActive;
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+    fn activate(self, framer: Framer) -> Result<Self::ActiveType, SpectrumError> {
+        let handle = thread::spawn(move || self.run(framer));
+        Ok(ThreadActive { _handle: handle })
+    }
+}
+
+/// Plays a `Signal` out of JACK until dropped, e.g. pink noise to measure a room with.
+pub struct SignalPlayer {
+    _client: jack::AsyncClient<Notifications, PlayHandler> // held so playback runs until drop
+}
+impl SignalPlayer {
+    /// Starts playing and connects to the physical playback ports, if there are any.
+    pub fn new_jack(signal: Signal) -> Result<Self, SpectrumError> {
+        let (client, _status) = match jack::Client::new("spectrum-player", jack::ClientOptions::NO_START_SERVER) {
+            Ok(ok) => ok,
+            Err(err) => return Err(SpectrumError::JackError(err))
+        };
+        let sample_rate = client.sample_rate();
+        let out_l = match client.register_port("left", jack::AudioOut::default()) {
+            Ok(ok) => ok,
+            Err(err) => return Err(SpectrumError::JackError(err))
+        };
+        let out_r = match client.register_port("right", jack::AudioOut::default()) {
+            Ok(ok) => ok,
+            Err(err) => return Err(SpectrumError::JackError(err))
+        };
+        let names = [out_l.name(), out_r.name()];
+        let handler = PlayHandler {
+            left: out_l,
+            right: out_r,
+            l_osc: Oscillator::new(signal, sample_rate, 0x1234_5678),
+            r_osc: Oscillator::new(signal, sample_rate, 0x8765_4321)
+        };
+        let active = match client.activate_async(Notifications, handler) {
+            Ok(active) => active,
+            Err(err) => return Err(SpectrumError::JackError(err))
+        };
+        let playback = active.as_client().ports(None, Some("audio"), jack::PortFlags::IS_INPUT | jack::PortFlags::IS_PHYSICAL);
+        if playback.is_empty() {
+            eprintln!("no playback ports to connect the signal to");
+        }
+        for (name, dest) in names.iter().zip(playback.iter()) {
+            let res = match name {
+                Ok(name) => active.as_client().connect_ports_by_name(name, dest),
+                Err(err) => Err(err.clone())
+            };
+            if let Err(err) = res {
+                eprintln!("unable to connect to {}: {:?}", dest, err);
+            }
+        }
+        Ok(SignalPlayer { _client: active })
+    }
+}
+
+/// The half spectrum bins covering `lo..hi` Hz: those whose centre frequency lies
+/// in the range, or the single bin closest to its middle if it is narrower than a bin.
+pub fn bin_range(lo: f32, hi: f32, sample_rate: usize, fft_len: usize) -> Range<usize> {
+    let bins = fft_len / 2 + 1;
+    let bin_hz = sample_rate as f32 / fft_len as f32;
+    // the first bin whose centre frequency is at or above f
+    let to_bin = |f: f32| ((f / bin_hz).ceil().max(0.0) as usize).min(bins);
+    let (first, last) = (to_bin(lo), to_bin(hi));
+    if first < last {
+        first..last
+    } else {
+        let centre = (((lo + hi.min(sample_rate as f32 / 2.0)) / 2.0 / bin_hz).round() as usize)
+            .max(1).min(bins - 1);
+        centre..centre + 1
+    }
+}
+
+/// The drum-like region of the spectrum an onset was found in.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum BeatBand {
+    Kick,
+    Snare,
+    HiHat
+}
+const BEAT_BANDS: [(BeatBand, f32, f32); 3] = [
+    (BeatBand::Kick, 30.0, 200.0),
+    (BeatBand::Snare, 200.0, 3000.0),
+    (BeatBand::HiHat, 6000.0, 16000.0)
+];
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Beat {
+    pub band: BeatBand,
+    pub confidence: f32, // 0 right at the threshold, approaching 1 for much stronger onsets
+    pub flux: f32
+}
+
+const RECENT: usize = 3;
+const TEMPO_WINDOW: f32 = 6.0; // seconds of onsets autocorrelated
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 180.0;
+const MIN_TEMPO_CONFIDENCE: f32 = 0.2;
+const CHROMA_LOW: f32 = 63.57; // a quarter tone below C2
+const CHROMA_HIGH: f32 = 5000.0;
+const MOMENTARY_BLOCKS: usize = 4; // of 100ms
+const SHORT_TERM_BLOCKS: usize = 30;
+const ABSOLUTE_GATE: f64 = -70.0; // LUFS
+const HISTOGRAM_BINS: usize = 1000; // 0.1 LU each up from the absolute gate
+
+/// Detects onsets from the rise in band level over the last few spectrograms 
+/// (spectral flux), compared per band against a threshold that adapts to the 
+/// last second or so of flux.
+pub struct OnsetDetector {
+    ranges: Vec<Range<usize>>,
+    recent: Vec<VecDeque<f32>>, // the last few levels of each band
+    history: Vec<VecDeque<f32>>,
+    history_len: usize,
+    refractory: usize, // blocks to wait after a beat before another in the same band
+    quiet: Vec<usize>,
+    flux: Vec<f32>,
+    sensitivity: f32
+}
+impl OnsetDetector {
+    /// `block_secs` is the time between successive calls to `process`.
+    pub fn new(sample_rate: usize, fft_len: usize, block_secs: f32) -> Self {
+        let ranges = BEAT_BANDS.iter().map(|b| bin_range(b.1, b.2, sample_rate, fft_len)).collect();
+        let history_len = ((1.0 / block_secs).round() as usize).max(4);
+        OnsetDetector {
+            ranges,
+            recent: vec![VecDeque::with_capacity(RECENT); BEAT_BANDS.len()],
+            history: vec![VecDeque::with_capacity(history_len); BEAT_BANDS.len()],
+            history_len,
+            refractory: ((0.1 / block_secs).round() as usize).max(1),
+            quiet: vec![0; BEAT_BANDS.len()],
+            flux: vec![0.0; BEAT_BANDS.len()],
+            sensitivity: 2.0
+        }
+    }
+    /// Standard deviations above the mean flux an onset has to reach, 2 by default.
+    pub fn set_sensitivity(&mut self, sensitivity: f32) {
+        self.sensitivity = sensitivity;
+    }
+    /// The flux of each band (kick, snare, hi-hat) from the last call to `process`.
+    pub fn flux(&self) -> &[f32] {
+        &self.flux
+    }
+    /// Takes the dB half spectrums from `StereoSample::spectrogram` and appends any beats to `beats`.
+    pub fn process(&mut self, left: &[f32], right: &[f32], beats: &mut Vec<Beat>) {
+        for (band, range) in self.ranges.iter().enumerate() {
+            // mix the channels by power and keep silence from producing infinite jumps
+            let power = range.clone()
+                .map(|k| 10f32.powf(left[k] / 10.0) + 10f32.powf(right[k] / 10.0))
+                .sum::<f32>() / (2 * range.len()) as f32;
+            let level = (10.0 * power.log10()).max(-100.0);
+            // rising over the loudest of the last few blocks rather than just the last one
+            // stops momentary dips in noisy bands from looking like onsets
+            let recent = &mut self.recent[band];
+            let flux = if recent.is_empty() {
+                0.0
+            } else {
+                (level - recent.iter().cloned().fold(f32::MIN, f32::max)).max(0.0)
+            };
+            if recent.len() == RECENT {
+                recent.pop_front();
+            }
+            recent.push_back(level);
+            self.flux[band] = flux;
+
+            let history = &mut self.history[band];
+            let n = history.len() as f32;
+            if history.len() == self.history_len {
+                let mean = history.iter().sum::<f32>() / n;
+                let var = history.iter().map(|f| (f - mean) * (f - mean)).sum::<f32>() / n;
+                // a floor on the threshold keeps steady noise from triggering beats
+                let threshold = (mean + self.sensitivity * var.sqrt()).max(3.0);
+                if flux > threshold && self.quiet[band] == 0 {
+                    beats.push(Beat { band: BEAT_BANDS[band].0, confidence: 1.0 - threshold / flux, flux });
+                    self.quiet[band] = self.refractory;
+                } else if self.quiet[band] > 0 {
+                    self.quiet[band] -= 1;
                 }
+                history.pop_front();
+            }
+            history.push_back(flux);
+        }
+    }
+}
+
+/// Estimates tempo from an onset strength signal, such as the summed 
+/// `OnsetDetector::flux`, by autocorrelating the last few seconds of it, 
+/// then follows the beat phase between estimates.
+pub struct TempoTracker {
+    onsets: VecDeque<f32>,
+    len: usize,
+    lags: Range<usize>, // beat periods considered, in blocks
+    update_every: usize,
+    count: usize,
+    block_secs: f32,
+    period: Option<f32>, // blocks per beat from the last confident estimate
+    since_beat: f32, // blocks since the last beat
+    confidence: f32
+}
+impl TempoTracker {
+    /// `block_secs` is the time between successive calls to `process`.
+    pub fn new(block_secs: f32) -> Self {
+        let len = ((TEMPO_WINDOW / block_secs).round() as usize).max(4);
+        let min_lag = ((60.0 / MAX_BPM / block_secs).floor() as usize).max(1);
+        let max_lag = ((60.0 / MIN_BPM / block_secs).ceil() as usize).min(len / 2).max(min_lag);
+        TempoTracker {
+            onsets: VecDeque::with_capacity(len),
+            len,
+            lags: min_lag..max_lag + 1,
+            update_every: ((0.25 / block_secs).round() as usize).max(1),
+            count: 0,
+            block_secs,
+            period: None,
+            since_beat: 0.0,
+            confidence: 0.0
+        }
+    }
+    /// Beats per minute, once there has been a confident estimate. It is kept
+    /// through silence and music without a clear beat.
+    pub fn bpm(&self) -> Option<f32> {
+        self.period.map(|p| 60.0 / (p * self.block_secs))
+    }
+    /// Blocks per beat, the `bpm` in units of calls to `process`.
+    pub fn period(&self) -> Option<f32> {
+        self.period
+    }
+    /// How far through the current beat we are, from 0 right on the beat up to 1.
+    pub fn phase(&self) -> f32 {
+        match self.period {
+            Some(p) => self.since_beat / p,
+            None => 0.0
+        }
+    }
+    /// 0 to 1, how strongly periodic the onsets were at the last estimate.
+    pub fn confidence(&self) -> f32 {
+        self.confidence
+    }
+    pub fn process(&mut self, onset: f32) {
+        if self.onsets.len() == self.len {
+            self.onsets.pop_front();
+        }
+        self.onsets.push_back(onset);
+        if let Some(p) = self.period {
+            self.since_beat += 1.0;
+            if self.since_beat >= p {
+                self.since_beat -= p;
+            }
+        }
+        self.count += 1;
+        // wait for a few beats worth before the first estimate
+        if self.count.is_multiple_of(self.update_every) && self.onsets.len() >= self.len / 2 {
+            self.estimate();
+        }
+    }
+    fn estimate(&mut self) {
+        let n = self.onsets.len();
+        let mean = self.onsets.iter().sum::<f32>() / n as f32;
+        let e: Vec<f32> = self.onsets.iter().map(|o| o - mean).collect();
+        let energy = e.iter().map(|x| x * x).sum::<f32>() / n as f32;
+        if energy <= 1e-9 {
+            self.confidence = 0.0;
+            return;
+        }
+        // normalized autocorrelation of each candidate period
+        let corr: Vec<f32> = self.lags.clone().map(|lag| {
+            let sum: f32 = (lag..n).map(|i| e[i] * e[i - lag]).sum();
+            sum / (n - lag) as f32 / energy
+        }).collect();
+        // favour tempos around 120 bpm so half and double time lose ties
+        let score = |i: usize| {
+            let bpm = 60.0 / ((self.lags.start + i) as f32 * self.block_secs);
+            corr[i] * (-0.5 * (bpm / 120.0).log2().powi(2)).exp()
+        };
+        let best = (0..corr.len()).fold(0, |b, i| if score(i) > score(b) { i } else { b });
+        self.confidence = corr[best].clamp(0.0, 1.0);
+        if self.confidence < MIN_TEMPO_CONFIDENCE {
+            return;
+        }
+        // parabolic interpolation between neighbouring lags
+        let mut period = (self.lags.start + best) as f32;
+        if best > 0 && best + 1 < corr.len() {
+            let (a, b, c) = (corr[best - 1], corr[best], corr[best + 1]);
+            let denom = a - 2.0 * b + c;
+            if denom < 0.0 {
+                period += (0.5 * (a - c) / denom).clamp(-0.5, 0.5);
+            }
+        }
+        // the offset back from now where a comb of beats collects the most onset strength
+        let lag = self.lags.start + best;
+        let comb = |o: usize| (o..n).step_by(lag).map(|k| self.onsets[n - 1 - k]).sum::<f32>();
+        let offset = (0..lag).fold(0, |b, o| if comb(o) > comb(b) { o } else { b });
+        self.period = Some(period);
+        self.since_beat = offset as f32 % period;
+    }
+}
+
+/// Folds a half spectrum into the 12 pitch classes, C first. Only bins narrow 
+/// enough to tell neighbouring semitones apart are counted, so longer FFTs 
+/// reach further down: at 48kHz it takes 16384 points to reach C2.
+pub struct Chroma {
+    classes: Vec<Option<usize>>, // pitch class of each bin
+    chroma: [f32; 12]
+}
+impl Chroma {
+    pub fn new(sample_rate: usize, fft_len: usize) -> Self {
+        let bin_hz = sample_rate as f32 / fft_len as f32;
+        let low = (bin_hz / (2f32.powf(1.0 / 12.0) - 1.0)).max(CHROMA_LOW);
+        let classes = (0..=fft_len / 2).map(|k| {
+            let f = k as f32 * bin_hz;
+            if f < low || f > CHROMA_HIGH {
+                None
+            } else {
+                let note = 69.0 + 12.0 * (f / 440.0).log2();
+                Some(note.round() as usize % 12)
+            }
+        }).collect();
+        Chroma { classes, chroma: [0.0; 12] }
+    }
+    /// Takes the dB half spectrums from `StereoSample::spectrogram` and returns
+    /// the power of each pitch class relative to the strongest.
+    pub fn process(&mut self, left: &[f32], right: &[f32]) -> &[f32; 12] {
+        self.chroma = [0.0; 12];
+        for (k, class) in self.classes.iter().enumerate() {
+            if let Some(c) = class {
+                self.chroma[*c] += 10f32.powf(left[k] / 10.0) + 10f32.powf(right[k] / 10.0);
+            }
+        }
+        let max = self.chroma.iter().cloned().fold(0.0, f32::max);
+        for c in self.chroma.iter_mut() {
+            *c = if max > 0.0 { *c / max } else { 0.0 };
+        }
+        &self.chroma
+    }
+    /// The strongest pitch class from the last call to `process`, None in silence.
+    pub fn dominant(&self) -> Option<usize> {
+        dominant_class(&self.chroma)
+    }
+}
+/// The index of the largest value of a chromagram, None if it is all zero.
+pub fn dominant_class(chroma: &[f32; 12]) -> Option<usize> {
+    let best = (0..12).fold(0, |b, i| if chroma[i] > chroma[b] { i } else { b });
+    if chroma[best] > 0.0 { Some(best) } else { None }
+}
+
+/// How the centres of a `Filterbank` are spaced.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum FilterScale {
+    /// Evenly in mels, close to linear below 1kHz and logarithmic above.
+    Mel,
+    /// Evenly in octaves.
+    Log
+}
+impl FilterScale {
+    fn hz_to_scale(self, hz: f32) -> f32 {
+        match self {
+            FilterScale::Mel => 2595.0 * (1.0 + hz / 700.0).log10(),
+            FilterScale::Log => hz.log2()
+        }
+    }
+    fn scale_to_hz(self, v: f32) -> f32 {
+        match self {
+            FilterScale::Mel => 700.0 * (10f32.powf(v / 2595.0) - 1.0),
+            FilterScale::Log => v.exp2()
+        }
+    }
+}
+
+/// Triangular filters over the bins of a half spectrum, each rising from the 
+/// previous filter's centre to its own and falling to the next one's, so the 
+/// bands are spaced the way pitch is heard rather than one bin apiece.
+pub struct Filterbank {
+    filters: Vec<(usize, Vec<f32>)>, // first bin and the weights from there
+    centres: Vec<f32>
+}
+impl Filterbank {
+    /// `bands` filters spanning `low` to `high` Hz.
+    pub fn new(scale: FilterScale, bands: usize, low: f32, high: f32, sample_rate: usize, fft_len: usize) -> Self {
+        let high = high.min(sample_rate as f32 / 2.0);
+        if bands == 0 || low <= 0.0 || high <= low {
+            panic!("need at least one band and 0 < low < high <= nyquist");
+        }
+        let bin_hz = sample_rate as f32 / fft_len as f32;
+        let (lo, hi) = (scale.hz_to_scale(low), scale.hz_to_scale(high));
+        let points: Vec<f32> = (0..bands + 2)
+            .map(|i| scale.scale_to_hz(lo + (hi - lo) * i as f32 / (bands + 1) as f32))
+            .collect();
+        let filters = points.windows(3).map(|p| {
+            let (left, centre, right) = (p[0], p[1], p[2]);
+            let bins = bin_range(left, right, sample_rate, fft_len);
+            let weights: Vec<f32> = bins.clone().map(|k| {
+                let f = k as f32 * bin_hz;
+                if f <= centre {
+                    (f - left) / (centre - left)
+                } else {
+                    (right - f) / (right - centre)
+                }.max(0.0)
+            }).collect();
+            if weights.iter().any(|w| *w > 0.0) {
+                (bins.start, weights)
+            } else {
+                // narrower than a bin, take the one nearest the centre
+                let k = ((centre / bin_hz).round() as usize).min(fft_len / 2);
+                (k, vec![1.0])
+            }
+        }).collect();
+        Filterbank { filters, centres: points[1..=bands].to_vec() }
+    }
+    /// Centre frequency of each filter in Hz.
+    pub fn centres(&self) -> &[f32] {
+        &self.centres
+    }
+    /// Takes a dB half spectrum such as one from `StereoSample::spectrogram` and 
+    /// returns the weighted mean power under each filter in dB.
+    pub fn process(&self, spec: &[f32]) -> Vec<f32> {
+        self.filters.iter().map(|(start, weights)| {
+            let mut power = 0.0;
+            for (w, db) in weights.iter().zip(&spec[*start..]) {
+                power += w * 10f32.powf(db / 10.0);
+            }
+            10.0 * (power / weights.iter().sum::<f32>()).log10()
+        }).collect()
+    }
+}
+
+/// A fundamental found by `PitchTracker`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Pitch {
+    pub freq: f32,
+    pub clarity: f32, // 0 to 1, how periodic the signal was at that frequency
+    pub level: f32 // rms of the sample in dB, 0 being a full scale square wave
+}
+
+/// Finds the fundamental of a single voice or instrument with the YIN difference 
+/// function over both channels mixed to mono. The lowest frequency it can find is 
+/// limited to two periods fitting in a sample, so give it `history_len` frames 
+/// to reach `min_freq`.
+pub struct PitchTracker {
+    sample_rate: usize,
+    min_freq: f32,
+    max_freq: f32,
+    threshold: f32,
+    gate: f32,
+    mono: Vec<f32>,
+    diff: Vec<f32>
+}
+impl PitchTracker {
+    pub fn new(sample_rate: usize, min_freq: f32, max_freq: f32) -> Self {
+        if min_freq <= 0.0 || max_freq <= min_freq {
+            panic!("need 0 < min_freq < max_freq");
+        }
+        PitchTracker { sample_rate, min_freq, max_freq, threshold: 0.15, gate: -60.0, mono: Vec::new(), diff: Vec::new() }
+    }
+    /// How aperiodic a signal may be and still have a pitch, 0.15 by default. 
+    /// Lower is stricter.
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
+    }
+    /// Samples quieter than `gate` dB rms have no pitch, -60 by default.
+    pub fn set_gate(&mut self, gate: f32) {
+        self.gate = gate;
+    }
+    /// Frames needed to find `min_freq`, two of its periods and the frame after.
+    pub fn history_len(&self) -> usize {
+        2 * ((self.sample_rate as f32 / self.min_freq).ceil() as usize + 1)
+    }
+    pub fn process(&mut self, left: &[f32], right: &[f32]) -> Option<Pitch> {
+        self.mono.clear();
+        self.mono.extend(left.iter().zip(right).map(|(l, r)| (l + r) * 0.5));
+        let len = self.mono.len();
+        let level = 10.0 * (self.mono.iter().map(|x| x * x).sum::<f32>() / len as f32).log10();
+        if len == 0 || level <= self.gate {
+            return None;
+        }
+        let min_tau = ((self.sample_rate as f32 / self.max_freq).floor() as usize).max(2);
+        let max_tau = ((self.sample_rate as f32 / self.min_freq).ceil() as usize).min(len / 2);
+        if min_tau + 2 > max_tau {
+            return None;
+        }
+        // cumulative mean normalized difference, d'(0) being 1
+        let width = len - max_tau;
+        self.diff.clear();
+        self.diff.push(1.0);
+        let mut running = 0.0;
+        for tau in 1..=max_tau {
+            let d: f32 = (0..width).map(|j| {
+                let delta = self.mono[j] - self.mono[j + tau];
+                delta * delta
+            }).sum();
+            running += d;
+            self.diff.push(if running > 0.0 { d * tau as f32 / running } else { 1.0 });
+        }
+        // the first dip under the threshold, followed down to its minimum
+        let mut tau = (min_tau..max_tau).find(|t| self.diff[*t] < self.threshold)?;
+        while tau + 1 < max_tau && self.diff[tau + 1] < self.diff[tau] {
+            tau += 1;
+        }
+        let (a, b, c) = (self.diff[tau - 1], self.diff[tau], self.diff[tau + 1]);
+        let denom = a - 2.0 * b + c;
+        let shift = if denom > 0.0 { (0.5 * (a - c) / denom).clamp(-0.5, 0.5) } else { 0.0 };
+        Some(Pitch {
+            freq: self.sample_rate as f32 / (tau as f32 + shift),
+            clarity: (1.0 - b).max(0.0),
+            level
+        })
+    }
+}
+
+// direct form I biquad in f64, the K-weighting needs the precision at low frequencies
+#[derive(Clone,Copy,Debug,Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2]
+}
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+// the BS.1770 K-weighting, a high shelf then a high pass, designed for any sample rate
+fn k_weighting(sample_rate: usize) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Biquad::default()
+    };
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        ..Biquad::default()
+    };
+    [shelf, high_pass]
+}
+
+/// Loudness in LUFS per EBU R128 / ITU-R BS.1770: momentary over the last 400ms, 
+/// short-term over the last 3s and integrated, gated, over everything so far. 
+/// All three are updated every 100ms.
+pub struct LoudnessMeter {
+    filters: [[Biquad; 2]; 2], // per channel
+    block: usize, // frames in 100ms
+    sum: f64, // weighted square sum of the current 100ms
+    frames: usize,
+    recent: VecDeque<f64>, // mean squares of the last 3s of 100ms blocks
+    histogram: Vec<(u64, f64)> // 400ms blocks seen in each 0.1 LU bin, and their summed mean squares
+}
+impl LoudnessMeter {
+    pub fn new(sample_rate: usize) -> Self {
+        let filters = k_weighting(sample_rate);
+        LoudnessMeter {
+            filters: [filters, filters],
+            block: (sample_rate / 10).max(1),
+            sum: 0.0,
+            frames: 0,
+            recent: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+            histogram: vec![(0, 0.0); HISTOGRAM_BINS]
+        }
+    }
+    /// Adds frames newer than any seen so far.
+    pub fn push(&mut self, left: &[f32], right: &[f32]) {
+        for (l, r) in left.iter().zip(right) {
+            let mut total = 0.0;
+            for (filters, x) in self.filters.iter_mut().zip([*l, *r].iter()) {
+                let shelved = filters[0].process(*x as f64);
+                let y = filters[1].process(shelved);
+                total += y * y;
+            }
+            self.sum += total;
+            self.frames += 1;
+            if self.frames == self.block {
+                self.end_block();
             }
         }
     }
+    fn end_block(&mut self) {
+        if self.recent.len() == SHORT_TERM_BLOCKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(self.sum / self.frames as f64);
+        self.sum = 0.0;
+        self.frames = 0;
+        // gating blocks are 400ms overlapping by 75%
+        if self.recent.len() >= MOMENTARY_BLOCKS {
+            let z = self.mean(MOMENTARY_BLOCKS);
+            let lufs = to_lufs(z);
+            if lufs > ABSOLUTE_GATE {
+                let bin = (((lufs - ABSOLUTE_GATE) * 10.0) as usize).min(HISTOGRAM_BINS - 1);
+                self.histogram[bin].0 += 1;
+                self.histogram[bin].1 += z;
+            }
+        }
+    }
+    fn mean(&self, blocks: usize) -> f64 {
+        if self.recent.len() < blocks {
+            return 0.0;
+        }
+        self.recent.iter().rev().take(blocks).sum::<f64>() / blocks as f64
+    }
+    /// LUFS over the last 400ms, -inf until there is that much.
+    pub fn momentary(&self) -> f32 {
+        to_lufs(self.mean(MOMENTARY_BLOCKS)) as f32
+    }
+    /// LUFS over the last 3s, -inf until there is that much.
+    pub fn short_term(&self) -> f32 {
+        to_lufs(self.mean(SHORT_TERM_BLOCKS)) as f32
+    }
+    /// Gated LUFS of everything so far, -inf until a block is above the absolute gate.
+    pub fn integrated(&self) -> f32 {
+        let gated = |threshold: f64| {
+            let (count, sum) = self.histogram.iter().enumerate()
+                .filter(|(i, _)| ABSOLUTE_GATE + (*i as f64 + 1.0) / 10.0 > threshold)
+                .fold((0, 0.0), |(c, s), (_, b)| (c + b.0, s + b.1));
+            if count == 0 { 0.0 } else { sum / count as f64 }
+        };
+        let relative = to_lufs(gated(ABSOLUTE_GATE)) - 10.0;
+        to_lufs(gated(relative)) as f32
+    }
+}
+fn to_lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// Settings for `SilenceDetector`. The threshold is in dBFS rms, hold times in milliseconds.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct SilenceConfig {
+    pub threshold: f32,
+    pub enter: f32, // how long the level has to stay below the threshold to be silent
+    pub exit: f32 // how long it has to stay above to end the silence
+}
+impl Default for SilenceConfig {
+    fn default() -> Self {
+        SilenceConfig { threshold: -60.0, enter: 3000.0, exit: 100.0 }
+    }
+}
+
+/// Decides whether a source has gone quiet from the rms level of each sample,
+/// with hold times so a pause between notes or a click in the silence doesn't
+/// flip it back and forth.
+pub struct SilenceDetector {
+    threshold: f32,
+    enter: usize, // in blocks
+    exit: usize,
+    silent: bool,
+    count: usize // blocks in a row on the other side of the threshold
+}
+impl SilenceDetector {
+    /// `block_secs` is the time between successive calls to `update`.
+    pub fn new(config: SilenceConfig, block_secs: f32) -> Self {
+        let blocks = |ms: f32| ((ms / 1000.0 / block_secs).round() as usize).max(1);
+        SilenceDetector {
+            threshold: config.threshold,
+            enter: blocks(config.enter),
+            exit: blocks(config.exit),
+            silent: false,
+            count: 0
+        }
+    }
+    /// Takes the level of the next sample in dBFS and returns whether the source is silent.
+    pub fn update(&mut self, level: f32) -> bool {
+        if (level < self.threshold) != self.silent {
+            self.count += 1;
+            if self.count >= if self.silent { self.exit } else { self.enter } {
+                self.silent = !self.silent;
+                self.count = 0;
+            }
+        } else {
+            self.count = 0;
+        }
+        self.silent
+    }
+    pub fn is_silent(&self) -> bool {
+        self.silent
+    }
+}
+
+/// The stereo image of a sample: mid and side levels in dB, how alike the channels
+/// are from -1 (opposite polarity) through 0 (unrelated) to 1 (mono), and the 
+/// balance from -1 (all left) to 1 (all right).
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct StereoImage {
+    pub mid: f32,
+    pub side: f32,
+    pub correlation: f32,
+    pub balance: f32
+}
+impl StereoImage {
+    fn new(left: &[f32], right: &[f32]) -> Self {
+        let (mut ll, mut rr, mut lr) = (0.0, 0.0, 0.0);
+        for (l, r) in left.iter().zip(right) {
+            ll += l * l;
+            rr += r * r;
+            lr += l * r;
+        }
+        let n = left.len().max(1) as f32;
+        // mid and side are (l + r) / 2 and (l - r) / 2
+        let mid = (ll + rr + 2.0 * lr) / 4.0 / n;
+        let side = (ll + rr - 2.0 * lr) / 4.0 / n;
+        let correlation = if ll > 0.0 && rr > 0.0 { lr / (ll * rr).sqrt() } else { 0.0 };
+        let balance = if ll + rr > 0.0 { (rr - ll) / (ll + rr) } else { 0.0 };
+        StereoImage { 
+            mid: 10.0 * mid.max(0.0).log10(), 
+            side: 10.0 * side.max(0.0).log10(), 
+            correlation: correlation.clamp(-1.0, 1.0), 
+            balance 
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct StereoSample {
     sample_size: usize,
     left: Vec<f32>,
-    right: Vec<f32>
+    right: Vec<f32>,
+    pool: Option<mpsc::SyncSender<StereoSample>> // where the buffers go when dropped
 
 }
 impl StereoSample {
@@ -87,7 +1464,8 @@ impl StereoSample {
         StereoSample {
             sample_size: sample_size,
             left: Vec::with_capacity(sample_size),
-            right: Vec::with_capacity(sample_size)
+            right: Vec::with_capacity(sample_size),
+            pool: None
         }
     }
     pub fn extend(&mut self, left: &[f32], right: &[f32]) -> bool {
@@ -117,62 +1495,402 @@ impl StereoSample {
         }
     }
     
-    pub fn spectrogram(&self, fft: &Radix4<f32>) -> (Vec<f32>, Vec<f32>) {
-        let mut l_in: Vec<Complex<f32>> = self.left.iter().map(|f| Complex::new(*f,0.0)).collect();
-        let mut r_in: Vec<Complex<f32>> = self.right.iter().map(|f| Complex::new(*f,0.0)).collect();
-        let mut l_out: Vec<Complex<f32>> = vec![Complex::zero(); self.sample_size];
-        let mut r_out: Vec<Complex<f32>> = vec![Complex::zero(); self.sample_size];
-        // proectss FFT of the two channels
-        fft.process_multi(&mut l_in, &mut l_out);
-        fft.process_multi(&mut r_in, &mut r_out);
-
-        /* normalize complex-valued amp and convert to amp-to-dB log_10 (amp^2).
-            Using norm_sqr() is a simplification that 
-            allows us to avoid an expensive sqrt operation for a value
-            we would either just sqaure before being input to log10() ( or double after the log10(0).
-        */
-        (l_out.into_iter().map(|c| c.norm_sqr().log10()*10.0).collect(),
-        r_out.into_iter().map(|c| c.norm_sqr().log10()*10.0).collect())
-    }
-    pub fn is_no_sig(&self) -> bool {
-        (self.left[0] == 0.0) && (self.right[0] == 0.0)
+    /// Power in dB of bins `0..=len/2` for each channel, averaged over the 
+    /// `analyzer.fft_len()` sized blocks of the sample. The slices live in the 
+    /// analyzer and are overwritten by the next call.
+    pub fn spectrogram<'a>(&self, analyzer: &'a mut Analyzer) -> (&'a [f32], &'a [f32]) {
+        analyzer.process(&self.left, &self.right)
+    }
+    /// Adds the newest `hop` frames of the sample to `meter`.
+    pub fn loudness(&self, meter: &mut LoudnessMeter, hop: usize) {
+        let start = self.left.len().saturating_sub(hop);
+        meter.push(&self.left[start..], &self.right[start..]);
+    }
+    pub fn pitch(&self, tracker: &mut PitchTracker) -> Option<Pitch> {
+        tracker.process(&self.left, &self.right)
+    }
+    /// Pitch of `history` after adding the newest `hop` frames of the sample to it,
+    /// for fundamentals too low to fit twice in a sample.
+    pub fn history_pitch(&self, history: &mut History, hop: usize, tracker: &mut PitchTracker) -> Option<Pitch> {
+        let start = self.left.len().saturating_sub(hop);
+        history.push(&self.left[start..], &self.right[start..]);
+        tracker.process(&history.left, &history.right)
+    }
+    /// Spectrogram of `history` after adding the newest `hop` frames of the sample 
+    /// to it, for transforms longer than a sample.
+    pub fn history_spectrogram<'a>(&self, history: &mut History, hop: usize, analyzer: &'a mut Analyzer) -> (&'a [f32], &'a [f32]) {
+        let start = self.left.len().saturating_sub(hop);
+        history.push(&self.left[start..], &self.right[start..]);
+        analyzer.process(&history.left, &history.right)
+    }
+    /// Constant-Q levels in dB after adding the newest `hop` frames of the sample 
+    /// to `cq`'s history. The slices live in `cq` like those of `spectrogram`.
+    pub fn constant_q<'a>(&self, cq: &'a mut ConstantQ, hop: usize) -> (&'a [f32], &'a [f32]) {
+        let start = self.left.len().saturating_sub(hop);
+        cq.push(&self.left[start..], &self.right[start..]);
+        cq.process()
+    }
+    pub fn stereo_image(&self) -> StereoImage {
+        StereoImage::new(&self.left, &self.right)
+    }
+    /// Rms level in dB of the louder channel, 0 being a full scale square wave.
+    pub fn rms_db(&self) -> f32 {
+        let rms = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>() / s.len().max(1) as f32;
+        10.0 * rms(&self.left).max(rms(&self.right)).log10()
     }
    
 }
+/// Window functions applied to each FFT block before transforming it.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    BlackmanHarris,
+    FlatTop
+}
+impl FromStr for Window {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rectangular" => Ok(Window::Rectangular),
+            "hann" => Ok(Window::Hann),
+            "hamming" => Ok(Window::Hamming),
+            "blackman-harris" => Ok(Window::BlackmanHarris),
+            "flat-top" => Ok(Window::FlatTop),
+            _ => Err(format!("unknown window {:?}", s))
+        }
+    }
+}
+impl Window {
+    // cosine-sum coefficients a0, a1, ... for w[n] = a0 - a1*cos(x) + a2*cos(2x) - ...
+    fn terms(self) -> &'static [f32] {
+        match self {
+            Window::Rectangular => &[1.0],
+            Window::Hann => &[0.5, 0.5],
+            Window::Hamming => &[0.54, 0.46],
+            Window::BlackmanHarris => &[0.35875, 0.48829, 0.14128, 0.01168],
+            Window::FlatTop => &[0.215_578_95, 0.416_631_58, 0.277_263_16, 0.083_578_95, 0.006_947_368]
+        }
+    }
+    /// How many times the noise of a rectangular window's bin one bin of this window 
+    /// collects, once `Analyzer` has undone the coherent gain.
+    pub fn noise_bandwidth(self, len: usize) -> f32 {
+        let w = self.coefficients(len);
+        len as f32 * w.iter().map(|x| x * x).sum::<f32>() / w.iter().sum::<f32>().powi(2)
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Window::Rectangular => "rectangular",
+            Window::Hann => "hann",
+            Window::Hamming => "hamming",
+            Window::BlackmanHarris => "blackman-harris",
+            Window::FlatTop => "flat-top"
+        }
+    }
+    /// The periodic (DFT-even) form of the window, which is the right one for spectral analysis.
+    pub fn coefficients(self, len: usize) -> Vec<f32> {
+        let terms = self.terms();
+        (0..len).map(|n| {
+            let x = 2.0 * std::f32::consts::PI * n as f32 / len as f32;
+            let mut w = 0.0;
+            for (k, a) in terms.iter().enumerate() {
+                let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                w += sign * a * (x * k as f32).cos();
+            }
+            w
+        }).collect()
+    }
+}
+
+/// The FFT, window and scratch space used by `StereoSample::spectrogram`. `len` 
+/// is the FFT length; samples longer than that are transformed as consecutive blocks
+/// and shorter ones are padded with silence.
+/// Since the input is real only half the spectrum is computed, by packing even and 
+/// odd samples into one `len / 2` point complex FFT and untangling the result.
+pub struct Analyzer {
+    fft: Radix4<f32>,
+    window: Vec<f32>,
+    gain: f32,
+    twiddles: Vec<Complex<f32>>, // e^(-2*pi*i*k/len) for k in 0..=len/2
+    input: Vec<Complex<f32>>,
+    output: Vec<Complex<f32>>,
+    left: Vec<f32>,
+    right: Vec<f32>
+}
+impl Analyzer {
+    pub fn new(len: usize, window: Window) -> Self {
+        if len < 4 || !len.is_power_of_two() {
+            panic!("FFT length must be a power of two of at least 4");
+        }
+        let window = window.coefficients(len);
+        let coherent = window.iter().sum::<f32>() / len as f32;
+        let half = len / 2;
+        Analyzer {
+            fft: Radix4::new(half, false),
+            window,
+            gain: 1.0 / (coherent * coherent),
+            twiddles: (0..=half).map(|k| {
+                let x = -2.0 * std::f32::consts::PI * k as f32 / len as f32;
+                Complex::new(x.cos(), x.sin())
+            }).collect(),
+            input: vec![Complex::zero(); half],
+            output: vec![Complex::zero(); half],
+            left: vec![0.0; half + 1],
+            right: vec![0.0; half + 1]
+        }
+    }
+    pub fn fft_len(&self) -> usize {
+        self.window.len()
+    }
+    /// Number of bins in each half spectrum, `fft_len() / 2 + 1`.
+    pub fn bins(&self) -> usize {
+        self.left.len()
+    }
+    pub fn process(&mut self, left: &[f32], right: &[f32]) -> (&[f32], &[f32]) {
+        let mut out = mem::take(&mut self.left);
+        self.half_spectrum(left, &mut out);
+        self.left = out;
+        let mut out = mem::take(&mut self.right);
+        self.half_spectrum(right, &mut out);
+        self.right = out;
+        (&self.left, &self.right)
+    }
+    fn half_spectrum(&mut self, samples: &[f32], out: &mut [f32]) {
+        let len = self.window.len();
+        let half = len / 2;
+        let padded;
+        let samples = if samples.len() < len {
+            padded = samples.iter().cloned().chain(std::iter::repeat(0.0)).take(len).collect::<Vec<f32>>();
+            &padded[..]
+        } else {
+            samples
+        };
+        for v in out.iter_mut() {
+            *v = 0.0;
+        }
+        let mut blocks = 0;
+        for block in samples.chunks_exact(len) {
+            for n in 0..half {
+                self.input[n] = Complex::new(block[2 * n] * self.window[2 * n], 
+                    block[2 * n + 1] * self.window[2 * n + 1]);
+            }
+            self.fft.process(&mut self.input, &mut self.output);
+            for (k, o) in out.iter_mut().enumerate() {
+                // split the packed transform back into the even and odd sample spectra
+                let z = self.output[k % half];
+                let zc = self.output[(half - k) % half].conj();
+                let even = (z + zc) * 0.5;
+                let odd = (z - zc) * Complex::new(0.0, -0.5);
+                let x = even + self.twiddles[k] * odd;
+                /* normalize complex-valued amp and convert to amp-to-dB log_10 (amp^2).
+                    Using norm_sqr() avoids a sqrt for a value we would square anyway.
+                    The gain undoes the window's coherent gain so levels match the rectangular window.
+                */
+                *o += (x.norm_sqr() * self.gain).log10() * 10.0;
+            }
+            blocks += 1;
+        }
+        if blocks > 1 {
+            for v in out.iter_mut() {
+                *v /= blocks as f32;
+            }
+        }
+    }
+}
+
+/// The most recent frames of both channels, oldest first, for analyses that need 
+/// more of them than a sample holds.
+pub struct History {
+    left: Vec<f32>,
+    right: Vec<f32>
+}
+impl History {
+    /// Starts out as `len` frames of silence.
+    pub fn new(len: usize) -> Self {
+        History { left: vec![0.0; len], right: vec![0.0; len] }
+    }
+    /// Adds frames newer than any seen so far, dropping as many of the oldest.
+    pub fn push(&mut self, left: &[f32], right: &[f32]) {
+        shift_in(&mut self.left, left);
+        shift_in(&mut self.right, right);
+    }
+    pub fn frames(&self) -> (&[f32], &[f32]) {
+        (&self.left, &self.right)
+    }
+}
+fn shift_in(history: &mut [f32], new: &[f32]) {
+    let len = history.len();
+    let new = &new[new.len().saturating_sub(len)..];
+    history.copy_within(new.len().., 0);
+    history[len - new.len()..].copy_from_slice(new);
+}
+
+/// Constant-Q spectrum with `bins_per_octave` bins per octave up from `min_freq`,
+/// each window just long enough to resolve a bin from its neighbours. Low bins 
+/// need windows far longer than a sample, so it keeps its own history of the 
+/// last `fft_len()` frames and transforms all of it with one FFT, then applies 
+/// each bin's kernel in the frequency domain.
+pub struct ConstantQ {
+    fft: Radix4<f32>,
+    freqs: Vec<f32>,
+    kernels: Vec<(usize, Vec<Complex<f32>>)>, // first FFT bin and the kernel from there
+    history: (Vec<f32>, Vec<f32>),
+    pos: usize, // next write position in the history ring
+    input: Vec<Complex<f32>>,
+    output: Vec<Complex<f32>>,
+    left: Vec<f32>,
+    right: Vec<f32>
+}
+impl ConstantQ {
+    pub fn new(sample_rate: usize, min_freq: f32, bins: usize, bins_per_octave: usize) -> Self {
+        let freqs: Vec<f32> = (0..bins).map(|k| min_freq * (k as f32 / bins_per_octave as f32).exp2()).collect();
+        if bins == 0 || bins_per_octave == 0 || min_freq <= 0.0 || freqs[bins - 1] >= sample_rate as f32 / 2.0 {
+            panic!("need at least one bin, all between 0 and nyquist");
+        }
+        let q = 1.0 / ((1.0 / bins_per_octave as f32).exp2() - 1.0);
+        let longest = (q * sample_rate as f32 / min_freq).ceil() as usize;
+        let len = longest.next_power_of_two().max(4);
+        let fft = Radix4::new(len, false);
+        let mut input = vec![Complex::zero(); len];
+        let mut output = vec![Complex::zero(); len];
+        let kernels = freqs.iter().map(|f| {
+            // a hann windowed complex tone over the most recent frames, normalized so a
+            // sine's bin reads half its amplitude
+            let n_k = ((q * sample_rate as f32 / f).ceil() as usize).min(len);
+            let window = Window::Hann.coefficients(n_k);
+            let sum: f32 = window.iter().sum();
+            for v in input.iter_mut() {
+                *v = Complex::zero();
+            }
+            for (m, w) in window.iter().enumerate() {
+                let x = 2.0 * std::f32::consts::PI * f * m as f32 / sample_rate as f32;
+                input[len - n_k + m] = Complex::new(x.cos(), x.sin()) * (w / sum);
+            }
+            fft.process(&mut input, &mut output);
+            // keep the span around the peak that matters, the rest is leakage
+            let peak = output.iter().map(|c| c.norm()).fold(0.0, f32::max);
+            let keep = |c: &Complex<f32>| c.norm() > peak * 1e-3;
+            let first = output.iter().position(keep).unwrap_or(0);
+            let last = output.iter().rposition(keep).unwrap_or(0);
+            (first, output[first..=last].iter().map(|c| c.conj() / len as f32).collect())
+        }).collect();
+        ConstantQ {
+            fft, freqs, kernels, input, output,
+            history: (vec![0.0; len], vec![0.0; len]),
+            pos: 0,
+            left: vec![0.0; bins],
+            right: vec![0.0; bins]
+        }
+    }
+    /// Frames of history transformed for each spectrum.
+    pub fn fft_len(&self) -> usize {
+        self.input.len()
+    }
+    /// Centre frequency of each bin in Hz.
+    pub fn freqs(&self) -> &[f32] {
+        &self.freqs
+    }
+    /// Adds frames newer than any seen so far to the history.
+    pub fn push(&mut self, left: &[f32], right: &[f32]) {
+        let len = self.input.len();
+        for (l, r) in left.iter().zip(right) {
+            self.history.0[self.pos] = *l;
+            self.history.1[self.pos] = *r;
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+    /// Level of each bin in dB for the current history, 0dB being a full scale sine.
+    pub fn process(&mut self) -> (&[f32], &[f32]) {
+        let len = self.input.len();
+        // both channels in one transform, left real and right imaginary, oldest first
+        for n in 0..len {
+            let i = (self.pos + n) % len;
+            self.input[n] = Complex::new(self.history.0[i], self.history.1[i]);
+        }
+        self.fft.process(&mut self.input, &mut self.output);
+        for (k, (first, kernel)) in self.kernels.iter().enumerate() {
+            let (mut l, mut r) = (Complex::<f32>::zero(), Complex::<f32>::zero());
+            for (j, c) in kernel.iter().enumerate() {
+                let z = self.output[first + j];
+                let zc = self.output[(len - first - j) % len].conj();
+                l += (z + zc) * 0.5 * c;
+                r += (z - zc) * Complex::new(0.0, -0.5) * c;
+            }
+            self.left[k] = (4.0 * l.norm_sqr()).log10() * 10.0;
+            self.right[k] = (4.0 * r.norm_sqr()).log10() * 10.0;
+        }
+        (&self.left, &self.right)
+    }
+}
+impl Drop for StereoSample {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            let ss = StereoSample {
+                sample_size: self.sample_size,
+                left: mem::take(&mut self.left),
+                right: mem::take(&mut self.right),
+                pool: None
+            };
+            let _ = pool.try_send(ss); // the framer may already be gone
+        }
+    }
+}
 #[derive(Debug)]
 pub enum SpectrumError {
     JackError(jack::Error),
+    IoError(io::Error),
     SpectrumError(&'static str)
 }
 
 struct FrameHandler {
-    sample: StereoSample,
-    sender: mpsc::SyncSender<StereoSample>,
+    framer: Framer,
     left: jack::Port<jack::AudioIn>,
-    right: jack::Port<jack::AudioIn>,
-    sample_size: usize
+    right: jack::Port<jack::AudioIn>
 }
 impl jack::ProcessHandler for FrameHandler {
     fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
         let in_l_sample = self.left.as_slice(ps);
         let in_r_sample = self.right.as_slice(ps);
-        if self.sample.extend(in_l_sample, in_r_sample) {
-            /* TODO: evaluate potential performance gains by eliminating allocation, 
-                that will be dropped every 8 audio frames. This is probably 
-                expensive and could in theory be replace by either some kind of 
-                mutex protected circular buffer or perhaps passing allocated Vec 
-                in a return mpsc-channel. Profiling needs to be done to determine 
-                where this thread is actually spending time. It could be that this 
-                is just a neccassaryly expensive component.
-            */
-            let mut ss = StereoSample::new(self.sample_size);
-            mem::swap(&mut ss, &mut self.sample); // in theory this should run without issue
-            self.sender.send(ss).unwrap();
+        if self.framer.push(in_l_sample, in_r_sample) {
+            jack::Control::Continue
+        } else {
+            jack::Control::Quit
+        }
+    }
+}
+struct PlayHandler {
+    left: jack::Port<jack::AudioOut>,
+    right: jack::Port<jack::AudioOut>,
+    l_osc: Oscillator,
+    r_osc: Oscillator
+}
+impl jack::ProcessHandler for PlayHandler {
+    fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        for v in self.left.as_mut_slice(ps).iter_mut() {
+            *v = self.l_osc.next();
+        }
+        for v in self.right.as_mut_slice(ps).iter_mut() {
+            *v = self.r_osc.next();
         }
         jack::Control::Continue
     }
 }
-impl Iterator for ActiveProducer {
+impl<S: InactiveSource> ActiveProducer<S> {
+    /// Frames in each sample.
+    pub fn sample_size(&self) -> usize {
+        self.sample_size
+    }
+    /// Frames between the starts of consecutive samples.
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+    /// Number of windows thrown away because the consumer fell behind.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+impl<S: InactiveSource> Iterator for ActiveProducer<S> {
     type Item = StereoSample;
     fn next(&mut self) -> Option<Self::Item> {
         match self.recv.recv() {
@@ -190,3 +1908,545 @@ impl jack::NotificationHandler for Notifications {
         jack::Control::Continue
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn wav_bytes(tag: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.extend_from_slice(b"RIFF");
+        v.extend_from_slice(&(36 + data.len() as u32 + 10).to_le_bytes());
+        v.extend_from_slice(b"WAVE");
+        v.extend_from_slice(b"LIST"); // unknown chunk with odd length, must be skipped with padding
+        v.extend_from_slice(&1_u32.to_le_bytes());
+        v.extend_from_slice(&[0, 0]);
+        v.extend_from_slice(b"fmt ");
+        v.extend_from_slice(&16_u32.to_le_bytes());
+        v.extend_from_slice(&tag.to_le_bytes());
+        v.extend_from_slice(&channels.to_le_bytes());
+        v.extend_from_slice(&44100_u32.to_le_bytes());
+        v.extend_from_slice(&(44100 * (bits / 8 * channels) as u32).to_le_bytes());
+        v.extend_from_slice(&(bits / 8 * channels).to_le_bytes());
+        v.extend_from_slice(&bits.to_le_bytes());
+        v.extend_from_slice(b"data");
+        v.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        v.extend_from_slice(data);
+        v
+    }
+
+    #[test]
+    fn test_wav_header_s16_stereo() {
+        let mut data = Vec::new();
+        for s in &[16384_i16, -16384, 0, 32767] {
+            data.extend_from_slice(&s.to_le_bytes());
+        }
+        let mut cur = Cursor::new(wav_bytes(1, 2, 16, &data));
+        let header = read_wav_header(&mut cur).unwrap();
+        assert_eq!(header, WavHeader { format: SampleFormat::S16, channels: 2, sample_rate: 44100, data_len: Some(8) });
+        let mut pcm = PcmReader::new(cur, header.format, header.channels, header.data_len);
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        assert!(pcm.read_frames(2, &mut left, &mut right).unwrap());
+        assert_eq!(left, vec![0.5, 0.0]);
+        assert_eq!(right, vec![-0.5, 32767.0 / 32768.0]);
+        assert!(!pcm.read_frames(2, &mut left, &mut right).unwrap());
+    }
+
+    #[test]
+    fn test_wav_mono_float_duplicates_channel() {
+        let mut data = Vec::new();
+        for s in &[0.25_f32, -1.0, 0.75] {
+            data.extend_from_slice(&s.to_le_bytes());
+        }
+        let mut cur = Cursor::new(wav_bytes(3, 1, 32, &data));
+        let header = read_wav_header(&mut cur).unwrap();
+        let mut pcm = PcmReader::new(cur, header.format, header.channels, header.data_len);
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        assert!(pcm.read_frames(2, &mut left, &mut right).unwrap());
+        assert_eq!(left, right);
+        assert_eq!(left, vec![0.25, -1.0]);
+        // the trailing frame cannot fill a block and is dropped
+        assert!(!pcm.read_frames(2, &mut left, &mut right).unwrap());
+    }
+
+    fn generate(left: Signal, right: Signal, blocks: usize) -> Vec<StereoSample> {
+        let (sender, recv) = mpsc::sync_channel(blocks + 1);
+        SignalGenerator::new(left, right, 48000, false)
+            .set_duration((blocks * 256) as f32 / 48000.0)
+            .run(Framer::new(256, 256, blocks + 1, sender));
+        recv.iter().collect()
+    }
+
+    #[test]
+    fn test_signal_parse() {
+        assert_eq!(Signal::from_str("sine:440"), Ok(Signal::Sine { freq: 440.0, amp: 0.5 }));
+        assert_eq!(Signal::from_str("sweep:20:20000:10:1"), 
+            Ok(Signal::Sweep { start: 20.0, end: 20000.0, secs: 10.0, amp: 1.0 }));
+        assert_eq!(Signal::from_str("silence"), Ok(Signal::Silence));
+        assert!(Signal::from_str("sine").is_err());
+        assert!(Signal::from_str("silence:1").is_err());
+        assert!(Signal::from_str("sweep:0:100:1").is_err());
+        assert!(Signal::from_str("square:100").is_err());
+    }
+
+    #[test]
+    fn test_signal_is_deterministic() {
+        let a = generate(Signal::PinkNoise { amp: 0.5 }, Signal::WhiteNoise { amp: 0.5 }, 4);
+        let b = generate(Signal::PinkNoise { amp: 0.5 }, Signal::WhiteNoise { amp: 0.5 }, 4);
+        assert_eq!(a.len(), 4);
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert_eq!(a.left, b.left);
+            assert_eq!(a.right, b.right);
+            assert!(a.left.iter().chain(a.right.iter()).all(|s| s.abs() <= 1.0));
+        }
+    }
+
+    #[test]
+    fn test_signal_sine_and_silence() {
+        let blocks = generate(Signal::Sine { freq: 375.0, amp: 1.0 }, Signal::Silence, 1);
+        let ss = &blocks[0];
+        assert!(ss.right.iter().all(|s| *s == 0.0));
+        // 375Hz at 48kHz is exactly 128 samples per cycle
+        assert!((ss.left[32] - 1.0).abs() < 1e-4);
+        assert!((ss.left[96] + 1.0).abs() < 1e-4);
+        assert!((ss.left[160] - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_pcm_stream_s16_quad() {
+        // four channel input, only the first two are used
+        let mut data = Vec::new();
+        for s in &[8192_i16, -8192, 1, 1, 0, 16384, 1, 1] {
+            data.extend_from_slice(&s.to_le_bytes());
+        }
+        let stream = PcmStream::new(Box::new(Cursor::new(data)), SampleFormat::from_str("s16le").unwrap(), 4, 8000);
+        let (sender, recv) = mpsc::sync_channel(4);
+        pump(stream.pcm, stream.sample_rate, false, Framer::new(2, 2, 4, sender));
+        let blocks: Vec<StereoSample> = recv.iter().collect();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].left, vec![0.25, 0.0]);
+        assert_eq!(blocks[0].right, vec![-0.25, 0.5]);
+    }
+
+    fn peak_and_leak(window: Window) -> (f32, f32) {
+        // 1031.25Hz sits half way between bins 5 and 6 of a 256 point FFT at 48kHz, the worst case for leakage
+        let blocks = generate(Signal::Sine { freq: 1031.25, amp: 1.0 }, Signal::Silence, 1);
+        let mut analyzer = Analyzer::new(256, window);
+        let (left, _) = blocks[0].spectrogram(&mut analyzer);
+        assert_eq!(left.len(), 129);
+        let peak = left.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        (peak, left[40])
+    }
+
+    #[test]
+    fn test_real_fft_matches_complex_fft() {
+        let blocks = generate(Signal::WhiteNoise { amp: 1.0 }, Signal::PinkNoise { amp: 0.25 }, 1);
+        let ss = &blocks[0];
+        let mut analyzer = Analyzer::new(64, Window::Rectangular);
+        let (left, right) = ss.spectrogram(&mut analyzer);
+        let fft = Radix4::new(64, false);
+        for (samples, half) in &[(&ss.left, left), (&ss.right, right)] {
+            let mut expected = [0.0; 33];
+            for block in samples.chunks(64) {
+                let mut input: Vec<Complex<f32>> = block.iter().map(|f| Complex::new(*f, 0.0)).collect();
+                let mut output = vec![Complex::zero(); 64];
+                fft.process(&mut input, &mut output);
+                for k in 0..33 {
+                    expected[k] += output[k].norm_sqr().log10() * 10.0 / 4.0;
+                }
+            }
+            for k in 0..33 {
+                assert!((half[k] - expected[k]).abs() < 0.01, "bin {}: {} vs {}", k, half[k], expected[k]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_analyzer_pads_short_samples() {
+        let mut analyzer = Analyzer::new(1024, Window::Rectangular);
+        // half a transform of a bin centred sine reads half the amplitude of a whole one
+        let tone: Vec<f32> = (0..512).map(|n| (2.0 * std::f32::consts::PI * 64.0 * n as f32 / 1024.0).sin()).collect();
+        let (left, _) = analyzer.process(&tone, &tone);
+        assert!((left[64] - 20.0 * 256.0_f32.log10()).abs() < 0.01, "{}", left[64]);
+        // and nothing at all is silence, not full scale
+        let (left, right) = analyzer.process(&[], &[]);
+        assert!(left.iter().chain(right).all(|l| *l == f32::NEG_INFINITY));
+    }
+
+    #[test]
+    fn test_window_amplitude_correction() {
+        // all windows must put the peak of a full scale sine at the same level (within scalloping loss)
+        let (rect, _) = peak_and_leak(Window::Rectangular);
+        for w in &[Window::Hann, Window::Hamming, Window::BlackmanHarris, Window::FlatTop] {
+            let (peak, _) = peak_and_leak(*w);
+            assert!((peak - rect).abs() < 4.0, "{:?}: {} vs {}", w, peak, rect);
+        }
+        let (flat, _) = peak_and_leak(Window::FlatTop);
+        assert!((flat - 20.0 * 128.0_f32.log10()).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_window_reduces_leakage() {
+        let (_, rect) = peak_and_leak(Window::Rectangular);
+        let (_, hann) = peak_and_leak(Window::Hann);
+        let (_, bh) = peak_and_leak(Window::BlackmanHarris);
+        assert!(hann < rect - 20.0);
+        assert!(bh < hann);
+    }
+
+    #[test]
+    fn test_framer_overlap() {
+        let (sender, recv) = mpsc::sync_channel(16);
+        let mut framer = Framer::new(4, 2, 16, sender);
+        let input: Vec<f32> = (0..9).map(|i| i as f32).collect();
+        // odd sized pushes must not change where windows start
+        assert!(framer.push(&input[0..3], &input[0..3]));
+        assert!(framer.push(&input[3..9], &input[3..9]));
+        drop(framer);
+        let windows: Vec<Vec<f32>> = recv.iter().map(|ss| ss.left.clone()).collect();
+        assert_eq!(windows, vec![
+            vec![0.0, 1.0, 2.0, 3.0],
+            vec![2.0, 3.0, 4.0, 5.0],
+            vec![4.0, 5.0, 6.0, 7.0]
+        ]);
+    }
+
+    #[test]
+    fn test_framer_no_overlap_matches_blocks() {
+        let (sender, recv) = mpsc::sync_channel(16);
+        let mut framer = Framer::new(3, 3, 16, sender);
+        let input: Vec<f32> = (0..7).map(|i| i as f32).collect();
+        assert!(framer.push(&input, &input));
+        drop(framer);
+        let windows: Vec<Vec<f32>> = recv.iter().map(|ss| ss.right.clone()).collect();
+        assert_eq!(windows, vec![vec![0.0, 1.0, 2.0], vec![3.0, 4.0, 5.0]]);
+    }
+
+    #[test]
+    fn test_producer_depth_ignores_hop() {
+        let source = || SignalGenerator::new(Signal::Silence, Signal::Silence, 48000, false);
+        assert_eq!(PendingProducer::new(source(), 1024).set_hop(128).depth, 46);
+        // small samples are capped rather than buffering a second of them
+        assert_eq!(PendingProducer::new(source(), 64).depth, MAX_DEPTH);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_producer_rejects_tiny_hop() {
+        PendingProducer::new(SignalGenerator::new(Signal::Silence, Signal::Silence, 48000, false), 1024).set_hop(64);
+    }
+
+    #[test]
+    fn test_framer_recycles_buffers() {
+        let (sender, recv) = mpsc::sync_channel(1);
+        let mut framer = Framer::new(2, 2, 1, sender);
+        framer.set_blocking(false);
+        assert!(framer.push(&[1.0, 2.0], &[1.0, 2.0]));
+        let first = recv.recv().unwrap();
+        let ptr = first.left.as_ptr();
+        drop(first);
+        // 2 more spare buffers are in the pool ahead of the one just returned
+        for i in 0..3 {
+            assert!(framer.push(&[i as f32; 2], &[0.0; 2]));
+            let ss = recv.recv().unwrap();
+            assert_eq!(ss.left, vec![i as f32; 2]);
+            if i == 2 {
+                assert_eq!(ss.left.as_ptr(), ptr);
+            }
+        }
+        assert_eq!(framer.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_framer_counts_dropped_when_full() {
+        let (sender, recv) = mpsc::sync_channel(1);
+        let mut framer = Framer::new(2, 2, 1, sender);
+        framer.set_blocking(false);
+        for _ in 0..4 {
+            assert!(framer.push(&[1.0, 2.0], &[1.0, 2.0]));
+        }
+        assert_eq!(framer.dropped.load(Ordering::Relaxed), 3);
+        assert_eq!(recv.try_iter().count(), 1);
+        drop(recv);
+        assert!(!framer.push(&[1.0, 2.0], &[1.0, 2.0]));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::new(4);
+        history.push(&[1.0, 2.0, 3.0], &[-1.0, -2.0, -3.0]);
+        assert_eq!(history.frames(), (&[0.0, 1.0, 2.0, 3.0][..], &[0.0, -1.0, -2.0, -3.0][..]));
+        history.push(&[4.0, 5.0], &[-4.0, -5.0]);
+        assert_eq!(history.frames().0, &[2.0, 3.0, 4.0, 5.0]);
+        // more than fits keeps the newest
+        history.push(&[6.0, 7.0, 8.0, 9.0, 10.0], &[0.0; 5]);
+        assert_eq!(history.frames().0, &[7.0, 8.0, 9.0, 10.0]);
+    }
+
+    #[test]
+    fn test_bin_range() {
+        assert_eq!(bin_range(150.0, 500.0, 48000, 256), 1..3);
+        assert_eq!(bin_range(3800.0, f32::INFINITY, 48000, 256), 21..129);
+        // narrower than a bin
+        assert_eq!(bin_range(30.0, 200.0, 48000, 256), 1..2);
+    }
+
+    #[test]
+    fn test_onset_detector_finds_kicks() {
+        // impulses roughly every 0.3s, landing at varying offsets within blocks, on a quiet noise bed
+        let sample_rate = 48000;
+        let blocks = generate(Signal::Impulse { secs: 0.301, amp: 1.0 }, Signal::WhiteNoise { amp: 0.001 }, 600);
+        let mut analyzer = Analyzer::new(256, Window::Hann);
+        let mut detector = OnsetDetector::new(sample_rate, 256, 256.0 / sample_rate as f32);
+        let mut beats = Vec::new();
+        let (mut found, mut expected) = (Vec::new(), Vec::new());
+        for (i, ss) in blocks.iter().enumerate() {
+            // near the block edges the hann window hides an impulse, it needn't be found there
+            if let Some(j) = ss.left.iter().position(|s| *s == 1.0) {
+                expected.push((i, j > 64 && j < 192));
+            }
+            let (left, right) = ss.spectrogram(&mut analyzer);
+            let before = beats.len();
+            detector.process(left, right, &mut beats);
+            if beats[before..].iter().any(|b| b.band == BeatBand::Kick) {
+                found.push(i);
+            }
+        }
+        // the first second only fills the history, after that kicks are only found at impulses
+        let expected: Vec<(usize, bool)> = expected.into_iter().filter(|e| e.0 >= 188).collect();
+        assert!(found.iter().all(|i| expected.iter().any(|e| e.0 == *i)), "{:?} {:?}", found, expected);
+        assert!(expected.iter().filter(|e| e.1).all(|e| found.contains(&e.0)), "{:?} {:?}", found, expected);
+        assert!(beats.iter().all(|b| b.confidence > 0.0 && b.confidence < 1.0));
+    }
+
+    #[test]
+    fn test_onset_detector_low_kick() {
+        // 100ms bursts of 60Hz every half second over a quiet noise bed, analyzed 
+        // like the strip does: 1024 frame windows every 256 frames
+        let sample_rate = 48000;
+        let mut noise = Oscillator::new(Signal::WhiteNoise { amp: 0.001 }, sample_rate, 1);
+        let samples: Vec<f32> = (0..sample_rate * 6).map(|n| {
+            let t = n as f32 / sample_rate as f32;
+            let burst = if t % 0.5 < 0.1 { (2.0 * std::f32::consts::PI * 60.0 * t).sin() * 0.5 } else { 0.0 };
+            burst + noise.next()
+        }).collect();
+        let mut analyzer = Analyzer::new(1024, Window::Hann);
+        let mut detector = OnsetDetector::new(sample_rate, 1024, 256.0 / sample_rate as f32);
+        let mut beats = Vec::new();
+        let mut kicks = Vec::new();
+        for (i, block) in samples.windows(1024).step_by(256).enumerate() {
+            let (left, right) = analyzer.process(block, block);
+            let before = beats.len();
+            detector.process(left, right, &mut beats);
+            if beats[before..].iter().any(|b| b.band == BeatBand::Kick) {
+                kicks.push((i * 256 + 1024) as f32 / sample_rate as f32); // time at the end of the window
+            }
+        }
+        // every burst once the first second has filled the history is a kick, found within 50ms
+        for burst in 3..12 {
+            let start = burst as f32 * 0.5;
+            assert!(kicks.iter().any(|t| *t >= start && *t < start + 0.05), "burst at {}s not found in {:?}", start, kicks);
+        }
+    }
+
+    #[test]
+    fn test_tempo_tracker_follows_beat() {
+        // an onset every 0.5s at 256 frame blocks of 48kHz
+        let block_secs = 256.0 / 48000.0;
+        let mut tempo = TempoTracker::new(block_secs);
+        let beat = |i: usize| (i as f32 / 93.75).fract() < 1.0 / 93.75;
+        for i in 0..1500 {
+            tempo.process(if beat(i) { 5.0 } else { (i % 7) as f32 * 0.05 });
+        }
+        assert!((tempo.bpm().unwrap() - 120.0).abs() < 2.0, "{:?}", tempo.bpm());
+        assert!(tempo.confidence() > 0.5);
+        // the phase wraps at the next onset
+        let mut i = 1500;
+        while !beat(i) {
+            tempo.process(0.0);
+            i += 1;
+        }
+        tempo.process(5.0);
+        assert!(tempo.phase() < 0.05 || tempo.phase() > 0.95, "{}", tempo.phase());
+        // silence leaves the last tempo in place
+        for _ in 0..2000 {
+            tempo.process(0.0);
+        }
+        assert!((tempo.bpm().unwrap() - 120.0).abs() < 2.0);
+        assert_eq!(tempo.confidence(), 0.0);
+        assert_eq!(TempoTracker::new(block_secs).bpm(), None);
+    }
+
+    #[test]
+    fn test_chroma_dominant_class() {
+        let analyze = |freq: f32| {
+            let tone: Vec<f32> = (0..16384).map(|n| (2.0 * std::f32::consts::PI * freq * n as f32 / 48000.0).sin() * 0.5).collect();
+            let mut analyzer = Analyzer::new(16384, Window::Hann);
+            let mut chroma = Chroma::new(48000, 16384);
+            let (left, right) = analyzer.process(&tone, &tone);
+            chroma.process(left, right);
+            chroma.dominant()
+        };
+        // the lowest octave counts as well as the higher ones
+        assert_eq!(analyze(65.41), Some(0)); // C2
+        assert_eq!(analyze(82.41), Some(4)); // E2
+        assert_eq!(analyze(110.0), Some(9)); // A2
+        assert_eq!(analyze(1046.5), Some(0)); // C6
+        assert_eq!(analyze(1318.5), Some(4)); // E6
+        assert_eq!(analyze(1760.0), Some(9)); // A6
+        assert_eq!(analyze(0.0), None);
+    }
+
+    #[test]
+    fn test_filterbank_spacing() {
+        let bank = Filterbank::new(FilterScale::Mel, 24, 100.0, 8000.0, 48000, 1024);
+        assert_eq!(bank.centres().len(), 24);
+        // 1000 Hz is 1000 mel, and the spacing widens with frequency
+        assert!((FilterScale::Mel.hz_to_scale(1000.0) - 1000.0).abs() < 0.5);
+        let c = bank.centres();
+        assert!(c[1] - c[0] < c[23] - c[22]);
+        let bank = Filterbank::new(FilterScale::Log, 6, 125.0, 16000.0, 48000, 1024);
+        for (c, want) in bank.centres().iter().zip([250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0].iter()) {
+            assert!((c - want).abs() < 0.1, "{} vs {}", c, want);
+        }
+        // low filters narrower than a bin still see something
+        let bank = Filterbank::new(FilterScale::Mel, 40, 30.0, 16000.0, 48000, 256);
+        let out = bank.process(&[-20.0; 129]);
+        assert!(out.iter().all(|db| (db + 20.0).abs() < 1e-3), "{:?}", out);
+    }
+
+    #[test]
+    fn test_filterbank_finds_tone() {
+        let bank = Filterbank::new(FilterScale::Mel, 24, 100.0, 8000.0, 48000, 1024);
+        let mut analyzer = Analyzer::new(1024, Window::Hann);
+        for band in [4, 10, 20].iter() {
+            let freq = bank.centres()[*band];
+            let tone: Vec<f32> = (0..1024).map(|n| (2.0 * std::f32::consts::PI * freq * n as f32 / 48000.0).sin()).collect();
+            let (left, _) = analyzer.process(&tone, &tone);
+            let out = bank.process(left);
+            let loudest = (0..out.len()).fold(0, |b, i| if out[i] > out[b] { i } else { b });
+            assert_eq!(loudest, *band);
+        }
+    }
+
+    #[test]
+    fn test_constant_q_resolves_semitones() {
+        // A2 up four octaves
+        let mut cq = ConstantQ::new(48000, 110.0, 48, 12);
+        assert_eq!(cq.fft_len(), 8192);
+        assert!((cq.freqs()[12] - 220.0).abs() < 0.01);
+        let tone: Vec<f32> = (0..cq.fft_len()).map(|n| (2.0 * std::f32::consts::PI * 233.08 * n as f32 / 48000.0).sin()).collect();
+        let silence = vec![0.0; tone.len()];
+        for (l, r) in tone.chunks(1024).zip(silence.chunks(1024)) {
+            cq.push(l, r);
+        }
+        let (left, right) = cq.process();
+        let loudest = (0..left.len()).fold(0, |b, i| if left[i] > left[b] { i } else { b });
+        assert_eq!(loudest, 13);
+        assert!(left[13].abs() < 0.5, "{}", left[13]);
+        // a semitone away is well down, a whole tone close to the window's null
+        assert!(left[12] < -5.0 && left[14] < -5.0, "{:?}", &left[10..17]);
+        assert!(left[11] < -25.0 && left[15] < -25.0, "{:?}", &left[10..17]);
+        assert!(right.iter().all(|r| *r < -60.0));
+    }
+
+    #[test]
+    fn test_pitch_tracker() {
+        let mut tracker = PitchTracker::new(48000, 80.0, 2000.0);
+        let tone = |freq: f32, harmonics: usize| -> Vec<f32> {
+            (0..1024).map(|n| (1..=harmonics).map(|k| {
+                (2.0 * std::f32::consts::PI * freq * k as f32 * n as f32 / 48000.0).sin() * 0.3 / k as f32
+            }).sum()).collect()
+        };
+        let sine = tone(220.0, 1);
+        let pitch = tracker.process(&sine, &sine).unwrap();
+        assert!((pitch.freq - 220.0).abs() < 0.5, "{:?}", pitch);
+        assert!(pitch.clarity > 0.9);
+        // the fundamental rather than the louder sum of harmonics
+        let saw = tone(110.0, 8);
+        let pitch = tracker.process(&saw, &saw).unwrap();
+        assert!((pitch.freq - 110.0).abs() < 0.5, "{:?}", pitch);
+        // noise and silence have none
+        let noise: Vec<f32> = generate(Signal::WhiteNoise { amp: 0.5 }, Signal::Silence, 4)
+            .iter().flat_map(|ss| ss.left.clone()).collect();
+        assert_eq!(tracker.process(&noise, &noise), None);
+        assert_eq!(tracker.process(&[0.0; 1024], &[0.0; 1024]), None);
+    }
+
+    #[test]
+    fn test_history_pitch() {
+        // E2 doesn't fit twice in 1024 frames at 48kHz, but does in the history
+        let mut tracker = PitchTracker::new(48000, 80.0, 2000.0);
+        let mut history = History::new(tracker.history_len());
+        let blocks = generate(Signal::Sine { freq: 82.41, amp: 0.5 }, Signal::Silence, 8);
+        let sample: Vec<f32> = blocks[..4].iter().flat_map(|ss| ss.left.clone()).collect();
+        assert_eq!(tracker.process(&sample, &sample), None);
+        let mut pitch = None;
+        for ss in &blocks {
+            pitch = ss.history_pitch(&mut history, 256, &mut tracker);
+        }
+        let pitch = pitch.unwrap();
+        assert!((pitch.freq - 82.41).abs() < 0.5, "{:?}", pitch);
+    }
+
+    #[test]
+    fn test_loudness_meter() {
+        // EBU Tech 3341 cases 1 and 3, a 1kHz stereo sine at -23dBFS then -36/-23/-36dBFS 
+        // for 10s/20s/10s all read -23 LUFS
+        let sine = |dbfs: f32, secs: usize, meter: &mut LoudnessMeter| {
+            let amp = 10f32.powf(dbfs / 20.0);
+            let tone: Vec<f32> = (0..48000).map(|n| (2.0 * std::f32::consts::PI * 1000.0 * n as f32 / 48000.0).sin() * amp).collect();
+            for _ in 0..secs {
+                meter.push(&tone, &tone);
+            }
+        };
+        let mut meter = LoudnessMeter::new(48000);
+        assert_eq!(meter.integrated(), f32::NEG_INFINITY);
+        sine(-23.0, 20, &mut meter);
+        for lufs in [meter.momentary(), meter.short_term(), meter.integrated()].iter() {
+            assert!((lufs + 23.0).abs() < 0.1, "{}", lufs);
+        }
+        let mut meter = LoudnessMeter::new(48000);
+        sine(-36.0, 10, &mut meter);
+        sine(-23.0, 20, &mut meter);
+        sine(-36.0, 10, &mut meter);
+        assert!((meter.integrated() + 23.0).abs() < 0.1, "{}", meter.integrated());
+        assert!((meter.momentary() + 36.0).abs() < 0.1, "{}", meter.momentary());
+    }
+
+    #[test]
+    fn test_silence_detector() {
+        // dither far below the threshold counts as silence
+        let noise = generate(Signal::WhiteNoise { amp: 0.001 }, Signal::Silence, 1);
+        assert!(noise[0].rms_db() < -60.0 && noise[0].rms_db() > -70.0, "{}", noise[0].rms_db());
+        // 10ms blocks, 50ms to enter and 20ms to exit
+        let mut silence = SilenceDetector::new(SilenceConfig { threshold: -60.0, enter: 50.0, exit: 20.0 }, 0.01);
+        let levels = [-80.0, -80.0, -80.0, -80.0, -20.0, -80.0, -80.0, -80.0, -80.0, -80.0, -20.0, -80.0, -20.0, -20.0];
+        let states: Vec<bool> = levels.iter().map(|l| silence.update(*l)).collect();
+        assert_eq!(states, vec![false, false, false, false, false, false, false, false, false, true, 
+            true, true, true, false]);
+        assert!(!silence.is_silent());
+    }
+
+    #[test]
+    fn test_stereo_image() {
+        let tone: Vec<f32> = (0..1024).map(|n| (n as f32 * 0.1).sin() * 0.5).collect();
+        let inverted: Vec<f32> = tone.iter().map(|x| -x).collect();
+        let silence = vec![0.0; 1024];
+        let mono = StereoImage::new(&tone, &tone);
+        assert!((mono.correlation - 1.0).abs() < 1e-4 && mono.balance == 0.0 && mono.side == f32::NEG_INFINITY);
+        assert!((mono.mid + 9.03).abs() < 0.05, "{}", mono.mid); // a 0.5 sine
+        let wide = StereoImage::new(&tone, &inverted);
+        assert!((wide.correlation + 1.0).abs() < 1e-4 && wide.mid == f32::NEG_INFINITY);
+        let left = StereoImage::new(&tone, &silence);
+        assert_eq!((left.correlation, left.balance), (0.0, -1.0));
+        assert!((left.mid - left.side).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_wav_rejects_non_riff() {
+        let mut cur = Cursor::new(b"RIFX\0\0\0\0WAVE".to_vec());
+        assert!(read_wav_header(&mut cur).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Measurements of an installation's input, taken once and applied to the spectrum at runtime.
+
+use crate::audio::{Analyzer, InactiveSource, PendingProducer, SpectrumError, Window};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+// the range a response correction is levelled over, in Hz, and the most it 
+// boosts or cuts any bin by in dB
+const RESPONSE_LOW: f32 = 100.0;
+const RESPONSE_HIGH: f32 = 10000.0;
+const MAX_CORRECTION: f32 = 12.0;
+// the quietest a response measurement may average over that range, in dB 
+// relative to full scale noise, below which it's silence or an unconnected input
+const MIN_RESPONSE_LEVEL: f32 = -80.0;
+
+/// A level in dB for each bin of a half spectrum, measured at one sample rate, FFT length and window.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Profile {
+    pub sample_rate: usize,
+    pub fft_len: usize,
+    pub window: Window,
+    pub levels: Vec<f32>
+}
+impl Profile {
+    /// Reads a profile written by `save`: the sample rate, FFT length and window on 
+    /// the first line, then one level per line. Lines starting with `#` are skipped.
+    /// Profiles saved without a window were recorded with a Hann window.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SpectrumError> {
+        let file = File::open(path).map_err(SpectrumError::IoError)?;
+        let mut lines = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(SpectrumError::IoError)?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                lines.push(line.to_string());
+            }
+        }
+        let bad = SpectrumError::SpectrumError("malformed calibration profile");
+        let mut header = match lines.first() {
+            Some(h) => h.split_whitespace(),
+            None => return Err(bad)
+        };
+        let (sample_rate, fft_len, window) = match (header.next(), header.next(), header.next()) {
+            (Some(rate), Some(len), window) => match (usize::from_str(rate), usize::from_str(len), window.map(Window::from_str)) {
+                (Ok(rate), Ok(len), None) if rate > 0 && len >= 2 => (rate, len, Window::Hann),
+                (Ok(rate), Ok(len), Some(Ok(window))) if rate > 0 && len >= 2 => (rate, len, window),
+                _ => return Err(bad)
+            },
+            _ => return Err(bad)
+        };
+        let levels = match lines[1..].iter().map(|l| f32::from_str(l)).collect::<Result<Vec<f32>, _>>() {
+            Ok(levels) if levels.len() == fft_len / 2 + 1 => levels,
+            _ => return Err(bad)
+        };
+        Ok(Profile { sample_rate, fft_len, window, levels })
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SpectrumError> {
+        let mut file = File::create(path).map_err(SpectrumError::IoError)?;
+        let mut out = format!("# spectrum calibration, dB per bin\n{} {} {}\n", self.sample_rate, self.fft_len, self.window.name());
+        for level in self.levels.iter() {
+            out.push_str(&format!("{}\n", level));
+        }
+        file.write_all(out.as_bytes()).map_err(SpectrumError::IoError)
+    }
+    /// The levels at the bins of another sample rate and FFT length, interpolated
+    /// linearly in frequency and held at the ends.
+    pub fn resample(&self, sample_rate: usize, fft_len: usize) -> Vec<f32> {
+        let from_hz = self.sample_rate as f32 / self.fft_len as f32;
+        let to_hz = sample_rate as f32 / fft_len as f32;
+        let last = self.levels.len() - 1;
+        (0..=fft_len / 2).map(|k| {
+            let x = k as f32 * to_hz / from_hz;
+            let i = (x.floor() as usize).min(last);
+            let j = (i + 1).min(last);
+            let t = (x - i as f32).min(1.0);
+            if t == 0.0 || self.levels[i] == self.levels[j] {
+                self.levels[i] // no arithmetic on infinite levels
+            } else {
+                self.levels[i] * (1.0 - t) + self.levels[j] * t
+            }
+        }).collect()
+    }
+    /// The levels of a recorded noise floor as an analyzer of another sample rate, 
+    /// FFT length and window reads the same noise. `Analyzer` undoes the window's 
+    /// coherent gain but not the length, so noise reads higher the longer the FFT 
+    /// and the wider the window's noise bandwidth.
+    pub fn noise_floor(&self, sample_rate: usize, fft_len: usize, window: Window) -> Vec<f32> {
+        let to = fft_len as f32 * window.noise_bandwidth(fft_len);
+        let from = self.fft_len as f32 * self.window.noise_bandwidth(self.fft_len);
+        let gain = 10.0 * (to / from).log10();
+        self.resample(sample_rate, fft_len).iter().map(|l| l + gain).collect()
+    }
+}
+
+/// Average power spectrum of both channels over `secs` seconds of the source,
+/// or as much as it has if it ends sooner, through a Hann window.
+pub fn record<S: InactiveSource>(source: PendingProducer<S>, secs: f32, fft_len: usize) -> Result<Profile, SpectrumError> {
+    let sample_rate = source.sample_rate();
+    let active = source.activate()?;
+    let blocks = ((secs * sample_rate as f32 / active.hop() as f32).ceil() as usize).max(1);
+    let window = Window::Hann;
+    let mut analyzer = Analyzer::new(fft_len, window);
+    let mut power = vec![0.0f64; analyzer.bins()];
+    let mut count = 0;
+    for ss in active.take(blocks) {
+        let (left, right) = ss.spectrogram(&mut analyzer);
+        for (p, (l, r)) in power.iter_mut().zip(left.iter().zip(right)) {
+            *p += 10f64.powf(*l as f64 / 10.0) + 10f64.powf(*r as f64 / 10.0);
+        }
+        count += 2;
+    }
+    if count == 0 {
+        return Err(SpectrumError::SpectrumError("no audio to calibrate from"));
+    }
+    let levels = power.iter().map(|p| (10.0 * (p / count as f64).log10()) as f32).collect();
+    Ok(Profile { sample_rate, fft_len, window, levels })
+}
+
+/// The correction in dB per bin that flattens `measured`, a recording of pink noise 
+/// played into the room. The measurement is smoothed over a third of an octave and 
+/// the correction averages 0dB between 100Hz and 10kHz. A measurement too quiet 
+/// to have heard the noise is an error.
+pub fn response(measured: &Profile) -> Result<Profile, SpectrumError> {
+    let bin_hz = measured.sample_rate as f32 / measured.fft_len as f32;
+    let last = measured.levels.len() - 1;
+    let power: Vec<f32> = measured.levels.iter().map(|l| 10f32.powf(l / 10.0)).collect();
+    let in_band: Vec<f32> = power.iter().enumerate()
+        .filter(|(k, _)| (RESPONSE_LOW..=RESPONSE_HIGH).contains(&(*k as f32 * bin_hz)))
+        .map(|(_, p)| *p).collect();
+    let mean = in_band.iter().sum::<f32>() / in_band.len() as f32;
+    let full_scale = measured.fft_len as f32 * measured.window.noise_bandwidth(measured.fft_len);
+    let level = 10.0 * (mean / full_scale).log10();
+    if !(level >= MIN_RESPONSE_LEVEL && level.is_finite()) {
+        return Err(SpectrumError::SpectrumError("response measurement is too quiet, is the input connected?"));
+    }
+    // pink noise has equal power per octave, so a flat room reads level plus 10log10(f)
+    let mut levels: Vec<f32> = (0..=last).map(|k| {
+        let k = k.max(1); // dc takes the lowest band's correction
+        let lo = ((k as f32 * 2f32.powf(-1.0 / 6.0)).round() as usize).max(1);
+        let hi = ((k as f32 * 2f32.powf(1.0 / 6.0)).round() as usize).min(last);
+        let smoothed = power[lo..=hi].iter().sum::<f32>() / (hi - lo + 1) as f32;
+        -(10.0 * smoothed.log10() + 10.0 * (k as f32 * bin_hz).log10())
+    }).collect();
+    let range: Vec<f32> = levels.iter().enumerate()
+        .filter(|(k, l)| (RESPONSE_LOW..=RESPONSE_HIGH).contains(&(*k as f32 * bin_hz)) && l.is_finite())
+        .map(|(_, l)| *l).collect();
+    let mean = if range.is_empty() { 0.0 } else { range.iter().sum::<f32>() / range.len() as f32 };
+    for l in levels.iter_mut() {
+        *l = (*l - mean).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+    }
+    Ok(Profile { sample_rate: measured.sample_rate, fft_len: measured.fft_len, window: measured.window, levels })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::{Signal, SignalGenerator};
+
+    #[test]
+    fn test_profile_round_trip() {
+        let profile = Profile { sample_rate: 48000, fft_len: 8, window: Window::Hamming, levels: vec![-10.0, -20.5, f32::NEG_INFINITY, 3.25, 0.0] };
+        let path = std::env::temp_dir().join(format!("spectrum-profile-{}.txt", std::process::id()));
+        profile.save(&path).unwrap();
+        assert_eq!(Profile::load(&path).unwrap(), profile);
+        // older profiles have no window
+        std::fs::write(&path, "48000 4\n1\n2\n3\n").unwrap();
+        assert_eq!(Profile::load(&path).unwrap().window, Window::Hann);
+        std::fs::write(&path, "48000 8\n1\n2\n").unwrap();
+        assert!(Profile::load(&path).is_err());
+        std::fs::write(&path, "48000 4 kaiser\n1\n2\n3\n").unwrap();
+        assert!(Profile::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_profile_resample() {
+        let profile = Profile { sample_rate: 48000, fft_len: 8, window: Window::Hann, levels: vec![0.0, 10.0, 20.0, 30.0, 40.0] };
+        // twice the bins at the same rate land half way between
+        assert_eq!(profile.resample(48000, 16), vec![0.0, 5.0, 10.0, 15.0, 20.0, 25.0, 30.0, 35.0, 40.0]);
+        // at twice the rate the upper half is past the measurement and held
+        assert_eq!(profile.resample(96000, 8), vec![0.0, 20.0, 40.0, 40.0, 40.0]);
+    }
+
+    #[test]
+    fn test_profile_noise_floor() {
+        let profile = Profile { sample_rate: 48000, fft_len: 1024, window: Window::Hann, levels: vec![-50.0; 513] };
+        // a quarter of the length is 6dB less noise, and the rectangular window's 
+        // bins are 1.5 times narrower than the Hann window's
+        let floor = profile.noise_floor(48000, 256, Window::Rectangular);
+        assert_eq!(floor.len(), 129);
+        assert!(floor.iter().all(|l| (l + 57.78).abs() < 0.01), "{:?}", floor);
+        assert_eq!(profile.noise_floor(48000, 1024, Window::Hann), profile.levels);
+    }
+
+    #[test]
+    fn test_record_white_noise() {
+        let source = SignalGenerator::new(Signal::WhiteNoise { amp: 0.5 }, Signal::WhiteNoise { amp: 0.5 }, 48000, false)
+            .set_duration(2.0);
+        let profile = record(PendingProducer::new(source, 1024), 1.0, 1024).unwrap();
+        assert_eq!((profile.sample_rate, profile.fft_len, profile.levels.len()), (48000, 1024, 513));
+        // flat apart from the edge bins, within the spread of a second of averaging
+        let mean = profile.levels[1..512].iter().sum::<f32>() / 511.0;
+        assert!(profile.levels[1..512].iter().all(|l| (l - mean).abs() < 2.0));
+    }
+
+    #[test]
+    fn test_response() {
+        let bin_hz = 48000.0 / 1024.0;
+        // pink noise through a room with a 6dB resonance around 1kHz
+        let levels = (0..513).map(|k| {
+            let f = (k.max(1) as f32) * bin_hz;
+            let bump = if (800.0..1250.0).contains(&f) { 6.0 } else { 0.0 };
+            40.0 - 10.0 * f.log10() + bump
+        }).collect();
+        let correction = response(&Profile { sample_rate: 48000, fft_len: 1024, window: Window::Hann, levels }).unwrap();
+        let at = |f: f32| correction.levels[(f / bin_hz).round() as usize];
+        assert!((at(1000.0) - at(5000.0) + 6.0).abs() < 0.5, "{} {}", at(1000.0), at(5000.0));
+        assert!((at(200.0) - at(5000.0)).abs() < 0.1);
+
+        // and the real thing, which is flat already
+        let source = SignalGenerator::new(Signal::PinkNoise { amp: 0.5 }, Signal::PinkNoise { amp: 0.5 }, 48000, false)
+            .set_duration(6.0);
+        let correction = response(&record(PendingProducer::new(source, 1024), 5.0, 1024).unwrap()).unwrap();
+        let in_range = &correction.levels[3..214]; // 140Hz to 10kHz
+        assert!(in_range.iter().all(|l| l.abs() < 1.5), "{:?}", in_range);
+    }
+
+    #[test]
+    fn test_response_of_silence() {
+        // a silent or unconnected input has nothing to correct
+        let source = SignalGenerator::new(Signal::Silence, Signal::Silence, 48000, false).set_duration(1.0);
+        assert!(response(&record(PendingProducer::new(source, 1024), 1.0, 1024).unwrap()).is_err());
+        let hiss = Profile { sample_rate: 48000, fft_len: 1024, window: Window::Hann, levels: vec![-60.0; 513] };
+        assert!(response(&hiss).is_err());
+    }
+}
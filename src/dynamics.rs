@@ -0,0 +1,237 @@
+//! Per-band level processing that sits between the spectrum and the LEDs.
+
+/// Settings for `Agc`. Times are in milliseconds, gains and ranges in dB.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct AgcConfig {
+    pub attack: f32, // how quickly the peak rises and the floor falls
+    pub release: f32, // how quickly the peak falls and the floor rises
+    pub min_gain: f32, // limits on the offset that moves the floor to 0
+    pub max_gain: f32,
+    pub min_range: f32 // smallest floor to peak span, stops noise being blown up to full scale
+}
+impl Default for AgcConfig {
+    fn default() -> Self {
+        AgcConfig { attack: 50.0, release: 3000.0, min_gain: 0.0, max_gain: 80.0, min_range: 24.0 }
+    }
+}
+
+// coefficient for a one pole smoother with time constant `ms` updated every `block_secs`
+pub(crate) fn coefficient(ms: f32, block_secs: f32) -> f32 {
+    if ms <= 0.0 {
+        0.0
+    } else {
+        (-block_secs * 1000.0 / ms).exp()
+    }
+}
+
+/// Automatic gain control. Tracks a rolling floor and peak of each band's level
+/// and reports the (floor, span) in dB that maps the band onto the full display range.
+pub struct Agc {
+    config: AgcConfig,
+    attack: f32,
+    release: f32,
+    floors: Vec<f32>,
+    peaks: Vec<f32>,
+    ranges: Vec<(f32, f32)>,
+    started: Vec<bool> // whether each band has had a finite level to start from
+}
+impl Agc {
+    /// `block_secs` is the time between successive calls to `update`.
+    pub fn new(config: AgcConfig, bands: usize, block_secs: f32) -> Self {
+        if config.min_gain > config.max_gain || config.min_range <= 0.0 {
+            panic!("min_gain must not exceed max_gain and min_range must be positive");
+        }
+        Agc {
+            config,
+            attack: coefficient(config.attack, block_secs),
+            release: coefficient(config.release, block_secs),
+            floors: vec![0.0; bands],
+            peaks: vec![0.0; bands],
+            ranges: vec![(-config.max_gain, config.min_range); bands],
+            started: vec![false; bands]
+        }
+    }
+    /// Feed one frame of band levels in dB. Both channels share a gain so the
+    /// stereo balance is kept.
+    pub fn update(&mut self, left: &[f32], right: &[f32]) {
+        for i in 0..self.floors.len() {
+            let level = left[i].max(right[i]);
+            if !level.is_finite() { // digital silence says nothing about the level
+                continue;
+            }
+            if !self.started[i] {
+                self.floors[i] = level;
+                self.peaks[i] = level;
+                self.started[i] = true;
+            }
+            let (floor, peak) = (&mut self.floors[i], &mut self.peaks[i]);
+            let a = if level < *floor { self.attack } else { self.release };
+            *floor = a * *floor + (1.0 - a) * level;
+            let a = if level > *peak { self.attack } else { self.release };
+            *peak = a * *peak + (1.0 - a) * level;
+
+            let gain = (-*floor).max(self.config.min_gain).min(self.config.max_gain);
+            let span = (*peak + gain).max(self.config.min_range);
+            self.ranges[i] = (-gain, span);
+        }
+    }
+    /// The (floor, span) of each band, levels at the floor map to 0 and 
+    /// `floor + span` to the top of the display.
+    pub fn ranges(&self) -> &[(f32, f32)] {
+        &self.ranges
+    }
+}
+
+// levels are clamped to this before smoothing so silence doesn't stick at -inf
+const MIN_DB: f32 = -150.0;
+
+/// Per-band envelope follower on levels in dB, so rises follow the attack time
+/// and falls the release time whatever rate the LEDs are refreshed at.
+pub struct Envelope {
+    attack: f32,
+    release: f32,
+    left: Vec<f32>,
+    right: Vec<f32>
+}
+impl Envelope {
+    /// `attack` and `release` are time constants in milliseconds, `block_secs` 
+    /// the time between successive calls to `process`.
+    pub fn new(attack: f32, release: f32, bands: usize, block_secs: f32) -> Self {
+        Envelope {
+            attack: coefficient(attack, block_secs),
+            release: coefficient(release, block_secs),
+            left: vec![MIN_DB; bands],
+            right: vec![MIN_DB; bands]
+        }
+    }
+    /// Smooths one frame of band levels in place.
+    pub fn process(&mut self, left: &mut [f32], right: &mut [f32]) {
+        for (state, levels) in [(&mut self.left, left), (&mut self.right, right)].iter_mut() {
+            for (s, l) in state.iter_mut().zip(levels.iter_mut()) {
+                let level = l.max(MIN_DB);
+                let a = if level > *s { self.attack } else { self.release };
+                *s = a * *s + (1.0 - a) * level;
+                *l = *s;
+            }
+        }
+    }
+}
+
+/// Settings for `LoudnessBrightness`. Levels are in LU relative to the integrated
+/// loudness, the smoothing time in milliseconds.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct LoudnessConfig {
+    pub quiet: f32, // at or below this the strip is at `floor` brightness
+    pub loud: f32, // at or above this it is at full brightness
+    pub floor: f32, // dimmest the strip gets, from 0 to 1
+    pub smoothing: f32
+}
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        LoudnessConfig { quiet: -15.0, loud: 3.0, floor: 0.15, smoothing: 200.0 }
+    }
+}
+
+/// Turns loudness into a brightness from `floor` to 1, so passages quieter than
+/// the music has been so far dim the strip and louder ones brighten it.
+pub struct LoudnessBrightness {
+    config: LoudnessConfig,
+    smoothing: f32,
+    brightness: f32
+}
+impl LoudnessBrightness {
+    /// `block_secs` is the time between successive calls to `update`.
+    pub fn new(config: LoudnessConfig, block_secs: f32) -> Self {
+        if config.loud <= config.quiet || config.floor < 0.0 || config.floor > 1.0 {
+            panic!("quiet must be below loud and floor within [0, 1]");
+        }
+        LoudnessBrightness { config, smoothing: coefficient(config.smoothing, block_secs), brightness: config.floor }
+    }
+    /// Takes the momentary and integrated loudness in LUFS and returns the brightness.
+    pub fn update(&mut self, momentary: f32, integrated: f32) -> f32 {
+        let c = &self.config;
+        // nothing above the gate yet is as quiet as it gets
+        let target = if momentary.is_finite() && integrated.is_finite() {
+            let x = (momentary - integrated - c.quiet) / (c.loud - c.quiet);
+            c.floor + (1.0 - c.floor) * x.clamp(0.0, 1.0)
+        } else {
+            c.floor
+        };
+        self.brightness = self.smoothing * self.brightness + (1.0 - self.smoothing) * target;
+        self.brightness
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_agc_follows_level() {
+        let mut agc = Agc::new(AgcConfig::default(), 1, 0.01);
+        // a quiet source settles with its peak near the top of the range
+        for i in 0..2000 {
+            let level = if i % 2 == 0 { -70.0 } else { -40.0 };
+            agc.update(&[level], &[level]);
+        }
+        let (floor, span) = agc.ranges()[0];
+        assert!(floor < -60.0 && floor > -70.5, "floor {}", floor);
+        assert!((floor + span) > -45.0 && (floor + span) < -40.0, "peak {}", floor + span);
+    }
+
+    #[test]
+    fn test_agc_starts_each_band() {
+        // the second band is silent at first, it starts from its first real level all the same
+        let mut agc = Agc::new(AgcConfig::default(), 2, 0.01);
+        agc.update(&[-30.0, f32::NEG_INFINITY], &[-30.0, f32::NEG_INFINITY]);
+        agc.update(&[-30.0, -50.0], &[-30.0, -50.0]);
+        assert_eq!((agc.floors[1], agc.peaks[1]), (-50.0, -50.0));
+    }
+
+    #[test]
+    fn test_envelope_attack_release() {
+        // 1ms attack, 100ms release at 10ms blocks
+        let mut env = Envelope::new(1.0, 100.0, 1, 0.01);
+        let (mut l, mut r) = ([0.0], [f32::NEG_INFINITY]);
+        env.process(&mut l, &mut r);
+        assert!(l[0] > -0.01, "attack too slow {}", l[0]);
+        assert_eq!(r[0], MIN_DB);
+        // one time constant of release covers ~63% of the drop
+        let mut fall = 0.0;
+        for _ in 0..10 {
+            let (mut l, mut r) = ([-100.0], [-100.0]);
+            env.process(&mut l, &mut r);
+            fall = l[0];
+        }
+        assert!((fall + 63.2).abs() < 0.5, "release {}", fall);
+    }
+
+    #[test]
+    fn test_agc_limits() {
+        let mut agc = Agc::new(AgcConfig::default(), 2, 0.01);
+        for _ in 0..2000 {
+            agc.update(&[-150.0, 20.0], &[f32::NEG_INFINITY, 20.0]);
+        }
+        // gain capped at max_gain for the very quiet band, min_gain and min_range for the loud one
+        assert_eq!(agc.ranges()[0], (-80.0, 24.0));
+        let (floor, span) = agc.ranges()[1];
+        assert_eq!(floor, 0.0);
+        assert!((span - 24.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_loudness_brightness() {
+        let mut lb = LoudnessBrightness::new(LoudnessConfig::default(), 0.01);
+        assert_eq!(lb.update(f32::NEG_INFINITY, f32::NEG_INFINITY), 0.15);
+        // settles at the mapped level, half way between quiet and loud
+        let mut b = 0.0;
+        for _ in 0..500 {
+            b = lb.update(-29.0, -23.0);
+        }
+        assert!((b - 0.575).abs() < 0.001, "{}", b);
+        for _ in 0..500 {
+            b = lb.update(-10.0, -23.0);
+        }
+        assert!((b - 1.0).abs() < 0.001, "{}", b);
+    }
+}
@@ -1,8 +1,12 @@
 use rs_ws281x;
 use crate::audio;
-use rustfft::algorithm::Radix4;
+use crate::calibration::Profile;
+use crate::visualmidi;
+use crate::dynamics::{coefficient,Agc,AgcConfig,Envelope,LoudnessBrightness,LoudnessConfig};
+use std::ops::Range;
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration,Instant};
 use random_color::RandomColor;
 
 //R color order
@@ -10,39 +14,127 @@ const COLORS: [[u8; 4]; 4] = [[0, 0, 255, 0],
                 [0, 128,128,0],
                 [0, 255, 0, 0],
                 [255, 0, 0, 0]];
-// Approximate ITU-R 468 weightings
-const WEIGHT: [f32; 256] = [0.0,-20.45,-14.43,-10.92,-8.43,-6.50,-4.93,-3.61,
-    -2.47,-1.47,-0.58,0.22,0.95,1.61,2.22,2.79,3.31,3.80,4.26,4.68,5.09,
-    5.47,5.83,6.17,6.49,6.80,7.09,7.37,7.64,7.89,8.14,
-    8.37,8.60,8.81,9.02,9.22,9.41,9.59,9.77,9.93,10.10,
-    10.25,10.40,10.54,10.68,10.81,10.94,11.06,11.17,11.27,11.38,
-    11.47,11.56,11.64,11.72,11.79,11.85,11.91,11.97,12.01,12.05,
-    12.09,12.12,12.14,12.16,12.17,12.18,12.18,12.17,12.16,12.15,
-    12.13,12.10,12.07,12.04,12.00,11.95,11.91,11.85,11.79,11.73,
-    11.67,11.60,11.52,11.44,11.36,11.27,11.18,11.08,10.98,10.87,
-    10.76,10.64,10.51,10.38,10.24,10.10,9.95,9.79,9.63,9.45,
-    9.27,9.08,8.89,8.68,8.47,8.25,8.02,7.78,7.54,7.28,
-    7.02,6.75,6.48,6.20,5.91,5.61,5.31,5.01,4.70,4.38,
-    4.06,3.74,3.41,3.09,2.76,2.42,2.09,1.75,1.41,1.08,
-    0.74,0.40,0.06,-0.28,-0.62,-0.96,-1.29,-1.63,-1.97,-2.30,
-    -2.63,-2.97,-3.30,-3.63,-3.95,-4.28,-4.60,-4.93,-5.25,-5.57,
-    -5.88,-6.20,-6.51,-6.83,-7.13,-7.44,-7.75,-8.05,-8.35,-8.65,
-    -8.95,-9.25,-9.54,-9.84,-10.13,-10.42,-10.70,-10.99,-11.27,-11.55,
-    -11.83,-12.11,-12.38,-12.66,-12.93,-13.20,-13.47,-13.74,-14.00,-14.27,
-    -14.53,-14.79,-15.05,-15.31,-15.56,-15.82,-16.07,-16.32,-16.57,-16.82,
-    -17.06,-17.31,-17.55,-17.79,-18.04,-18.27,-18.51,-18.75,-18.98,-19.22,
-    -19.45,-19.68,-19.91,-20.14,-20.37,-20.59,-20.82,-21.04,-21.27,-21.49,
-    -21.71,-21.93,-22.14,-22.36,-22.58,-22.79,-23.00,-23.21,-23.43,-23.64,
-    -23.84,-24.05,-24.26,-24.46,-24.67,-24.87,-25.08,-25.28,-25.48,-25.68,
-    -25.88,-26.07,-26.27,-26.47,-26.66,-26.86,-27.05,-27.24,-27.43,-27.62,
-    -27.81,-28.00,-28.19,-28.38,-28.56,-28.75,-28.93,-29.12,-29.30,-29.48,
-    -29.66,-29.84,-30.02,-30.20,-30.38];
+// (floor, span) in dB used without automatic gain control
+const KEY_FLOOR: f32 = -70.0; // quietest key level lit, full scale being 0dB
+const KEY_RANGE: f32 = 30.0; // how far below the loudest key others are still lit
+const PIANO_LOW_KEY: usize = 12; // lowest key the piano mode measures, counted up from A0
+const FIXED_RANGE: (f32, f32) = (-40.0, 50.0);
+// power subtraction of a calibrated noise floor: the multiple of it taken away, and the 
+// fraction of it left where that would go below zero
+const NOISE_OVERSUBTRACT: f32 = 2.0;
+const NOISE_RESIDUE: f32 = 0.01;
+// (floor, span) in dB of the stereo image's brightness, and its smoothing in ms
+const STEREO_RANGE: (f32, f32) = (-60.0, 60.0);
+const STEREO_SMOOTHING: f32 = 150.0;
+// period in seconds of the silence pulse while there is no tempo to follow
+const PULSE_SECS: f32 = 2.7;
+// Edges of the default subwoofer, woofer, midrange and tweeter bands in Hz. At 48kHz 
+// with a 256 point FFT these select bins 1..3, 3..6, 6..21 and 21..
+const BAND_EDGES: [f32; 5] = [150.0, 500.0, 1000.0, 3800.0, f32::INFINITY];
+// longest FFT, and history of frames, the bands are measured with
+const MAX_BAND_FFT: usize = 16384;
+// frames of history the chroma mode folds into pitch classes
+const CHROMA_FFT: usize = 16384;
+/// Frequency weighting curves applied to the spectrum before picking band levels.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Weighting {
+    Flat,
+    A,
+    C,
+    Itu468
+}
+impl Weighting {
+    /// Gain in dB at `f` Hz, from the formulas in IEC 61672 (A and C) and ITU-R BS.468.
+    pub fn gain_db(self, f: f64) -> f32 {
+        let f2 = f * f;
+        let db = match self {
+            Weighting::Flat => return 0.0,
+            Weighting::A => {
+                let r = 12194.0_f64.powi(2) * f2 * f2 / ((f2 + 20.6_f64.powi(2)) 
+                    * ((f2 + 107.7_f64.powi(2)) * (f2 + 737.9_f64.powi(2))).sqrt() * (f2 + 12194.0_f64.powi(2)));
+                20.0 * r.log10() + 2.0
+            },
+            Weighting::C => {
+                let r = 12194.0_f64.powi(2) * f2 / ((f2 + 20.6_f64.powi(2)) * (f2 + 12194.0_f64.powi(2)));
+                20.0 * r.log10() + 0.06
+            },
+            Weighting::Itu468 => {
+                let h1 = -4.737_338_981_378_384e-24 * f2.powi(3) + 2.043_828_333_606_125e-15 * f2 * f2
+                    - 1.363_894_795_463_638e-7 * f2 + 1.0;
+                let h2 = 1.306_612_257_412_824e-19 * f2 * f2 * f - 2.118_150_887_518_656e-11 * f2 * f
+                    + 5.559_488_023_498_642e-4 * f;
+                let r = 1.246_332_637_532_143e-4 * f / (h1 * h1 + h2 * h2).sqrt();
+                18.2 + 20.0 * r.log10()
+            }
+        };
+        db as f32
+    }
+}
+
+/// One frequency band of the audio visualizer. A band runs from the previous 
+/// band's `upper` crossover (or the low cut for the first band) up to its own.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Band {
+    pub upper: f32,
+    pub color: [u8; 4]
+}
+impl Band {
+    /// `n` bands spaced evenly on a log scale between `low` and `high` Hz, the last 
+    /// one open ended, colored from blue for the lowest to red for the highest.
+    pub fn log_spaced(n: usize, low: f32, high: f32) -> Vec<Band> {
+        if n == 0 || low <= 0.0 || high <= low {
+            panic!("need at least one band and 0 < low < high");
+        }
+        (1..=n).map(|i| {
+            let upper = if i == n { f32::INFINITY } else { low * (high / low).powf(i as f32 / n as f32) };
+            let hue = if n == 1 { 0.0 } else { 240.0 * (1.0 - (i - 1) as f32 / (n - 1) as f32) };
+            Band { upper, color: hue_to_color(hue) }
+        }).collect()
+    }
+}
+// fully saturated color for a hue in degrees, 0 being red and 240 blue
+fn hue_to_color(hue: f32) -> [u8; 4] {
+    let h = (hue % 360.0) / 60.0;
+    let x = 1.0 - (h % 2.0 - 1.0).abs();
+    let (r, g, b) = match h as usize {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x)
+    };
+    [(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8, 0]
+}
+fn scale_color(color: [u8; 4], brightness: f32) -> [u8; 4] {
+    let mut c = color;
+    for v in c.iter_mut() {
+        *v = (*v as f32 * brightness) as u8;
+    }
+    c
+}
+fn default_bands() -> Vec<Band> {
+    BAND_EDGES[1..].iter().zip(COLORS.iter()).map(|(upper, color)| Band { upper: *upper, color: *color }).collect()
+}
 
 pub struct Controller {
    controller: rs_ws281x::Controller,
    invert: bool,
-   colors: [[u8; 4]; 4],
+   low_cut: f32,
+   bands: Vec<Band>,
    alg: Algorithm,
+   weighting: Weighting,
+   agc: Option<AgcConfig>,
+   envelope: Option<(f32, f32)>,
+   peak: Option<PeakConfig>,
+   beat_flash: Option<[u8; 4]>,
+   filterbank: Option<(audio::FilterScale, f32, f32)>,
+   loudness: Option<LoudnessConfig>,
+   silence: audio::SilenceConfig,
+   noise_floor: Option<Profile>,
+   response: Option<Profile>,
+   mode: Mode,
+   window: audio::Window,
    pulse: bool,
    brightness: f32,
 }
@@ -59,17 +151,24 @@ impl Controller {
         let controller = rs_ws281x::ControllerBuilder::new()
             .freq(800_000)
             .channel(0, channel).build().unwrap();
-        let mut colors = [[0; 4]; 4];
-        for i in 0..4 {
-            for j in 0..4 {
-                colors[i][j] = ((COLORS[i][j] as f32) * brightness) as u8;
-            }
-        }
         Controller {
             controller: controller,
             invert: invert,
-            colors: colors,
+            low_cut: BAND_EDGES[0],
+            bands: default_bands(),
             alg: Algorithm::Linear,
+            weighting: Weighting::Itu468,
+            agc: None,
+            envelope: None,
+            peak: None,
+            beat_flash: None,
+            filterbank: None,
+            loudness: None,
+            silence: audio::SilenceConfig::default(),
+            noise_floor: None,
+            response: None,
+            mode: Mode::Bands,
+            window: audio::Window::Rectangular,
             pulse: pulse,
             brightness: brightness
         }
@@ -78,47 +177,287 @@ impl Controller {
         self.alg = alg;
         self
     }
-    pub fn display(mut self, source: audio::PendingProducer) -> Self {
+    /// Replace the default four bands. `low_cut` is the lower edge of the first band,
+    /// each half of the strip must have at least one LED per band.
+    pub fn set_bands(mut self, low_cut: f32, bands: Vec<Band>) -> Self {
+        if bands.is_empty() || bands.len() * 2 > self.controller.leds(0).len() {
+            panic!("need between 1 and half the led count bands");
+        }
+        let mut lower = low_cut;
+        for band in bands.iter() {
+            if band.upper <= lower {
+                panic!("band crossovers must be ascending");
+            }
+            lower = band.upper;
+        }
+        self.low_cut = low_cut;
+        self.bands = bands;
+        self
+    }
+    /// Automatic gain control instead of mapping -40dB..10dB onto each band.
+    pub fn set_agc(mut self, agc: Option<AgcConfig>) -> Self {
+        self.agc = agc;
+        self
+    }
+    /// Smooth each band with an (attack, release) envelope follower, times in milliseconds.
+    pub fn set_envelope(mut self, envelope: Option<(f32, f32)>) -> Self {
+        self.envelope = envelope;
+        self
+    }
+    /// Draw a peak-hold dot above each channel's bar.
+    pub fn set_peak(mut self, peak: Option<PeakConfig>) -> Self {
+        self.peak = peak;
+        self
+    }
+    /// Light the unlit LEDs in `color` on each kick, fading out over a few hundred ms.
+    pub fn set_beat_flash(mut self, color: Option<[u8; 4]>) -> Self {
+        self.beat_flash = color;
+        self
+    }
+    /// Measure the bands with a filterbank of (scale, low Hz, high Hz) instead of 
+    /// the loudest bin between crossovers. The band colors are kept, their crossovers 
+    /// ignored, and the whole sample goes into one transform for finer low bands.
+    pub fn set_filterbank(mut self, filterbank: Option<(audio::FilterScale, f32, f32)>) -> Self {
+        self.filterbank = filterbank;
+        self
+    }
+    /// Scale the brightness by how loud the music is compared to how loud it has been.
+    pub fn set_loudness(mut self, loudness: Option<LoudnessConfig>) -> Self {
+        self.loudness = loudness;
+        self
+    }
+    /// When the input counts as silent, for the pulse that takes over from the spectrum.
+    pub fn set_silence(mut self, silence: audio::SilenceConfig) -> Self {
+        self.silence = silence;
+        self
+    }
+    /// Subtract a recorded noise floor from the spectrum before measuring the bands.
+    pub fn set_noise_floor(mut self, noise_floor: Option<Profile>) -> Self {
+        self.noise_floor = noise_floor;
+        self
+    }
+    /// Add a frequency response correction from `calibration::response` to the 
+    /// weighting, so the room and microphone don't favor some bands.
+    pub fn set_response(mut self, response: Option<Profile>) -> Self {
+        self.response = response;
+        self
+    }
+    pub fn set_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+    pub fn set_weighting(mut self, weighting: Weighting) -> Self {
+        self.weighting = weighting;
+        self
+    }
+    pub fn set_window(mut self, window: audio::Window) -> Self {
+        self.window = window;
+        self
+    }
+    pub fn display<S: audio::InactiveSource + 'static>(mut self, source: audio::PendingProducer<S>) -> Self {
+        let sample_rate = source.sample_rate();
         let mut active = source.activate().unwrap(); 
         let (sender, recv) = mpsc::sync_channel(1);
         let led_num = self.controller.leds(0).len();
         let invert = self.invert;
-        let colors = self.colors;
+        let mut edges = vec![self.low_cut];
+        edges.extend(self.bands.iter().map(|b| b.upper));
+        let colors: Vec<[u8; 4]> = self.bands.iter().map(|b| scale_color(b.color, self.brightness)).collect();
         let alg = self.alg;
+        let window = self.window;
+        let weighting = self.weighting;
+        let agc_config = self.agc;
+        let envelope = self.envelope;
+        let peak = self.peak.map(|mut p| {
+            p.color = scale_color(p.color, self.brightness);
+            p
+        });
+        let beat_flash = self.beat_flash.map(|c| scale_color(c, self.brightness));
+        let filterbank = self.filterbank;
+        let loudness = self.loudness;
+        let silence_config = self.silence;
+        let noise_floor = self.noise_floor.take();
+        let response = self.response.take();
+        let mode = self.mode;
         let pulse = self.pulse;
         let brightness = self.brightness;
         thread::spawn(move || {
-            let mut planner = Radix4::new(256, false);
-            let mut start_time = 0;
+            let fft_len = if filterbank.is_some() { active.sample_size() } else { 
+                band_fft_len(&edges, sample_rate).unwrap_or_else(|| {
+                    eprintln!("the bands are too narrow to tell apart at {}Hz, some will show the same level", sample_rate);
+                    MAX_BAND_FFT
+                })
+            };
+            let mut analyzer = audio::Analyzer::new(fft_len, window);
+            // bands finer than a sample resolves are measured over a longer history
+            let mut history = if fft_len > active.sample_size() { Some(audio::History::new(fft_len)) } else { None };
+            let mut bands = match filterbank {
+                Some((scale, low, high)) => {
+                    let filters = audio::Filterbank::new(scale, colors.len(), low, high, sample_rate, fft_len);
+                    BandMap::with_filterbank(filters, weighting, sample_rate, fft_len)
+                },
+                None => BandMap::new(&edges, weighting, sample_rate, fft_len)
+            };
+            if let Some(profile) = &response {
+                bands.add_response(&profile.resample(sample_rate, fft_len));
+            }
+            if let Some(profile) = &noise_floor {
+                bands.set_noise_floor(&profile.noise_floor(sample_rate, fft_len, window));
+            }
+            let block_secs = active.hop() as f32 / sample_rate as f32;
+            let mut agc = agc_config.map(|c| Agc::new(c, colors.len(), block_secs));
+            let mut env = envelope.map(|(a, r)| Envelope::new(a, r, colors.len(), block_secs));
+            let mut peaks = peak.map(PeakHold::new);
+            let mut flash = beat_flash.map(|c| BeatFlash::new(c, block_secs));
+            // the pulse keeps time with the music it last heard
+            let mut tempo = if pulse { Some(audio::TempoTracker::new(block_secs)) } else { None };
+            // onsets get a transform of the whole sample so kicks below 100Hz are resolved
+            let mut onsets = if flash.is_some() || tempo.is_some() {
+                Some((audio::Analyzer::new(active.sample_size(), audio::Window::Hann),
+                    audio::OnsetDetector::new(sample_rate, active.sample_size(), block_secs)))
+            } else {
+                None
+            };
+            let mut beats = Vec::new();
+            let mut chroma = match mode {
+                Mode::Chroma => Some(ChromaColor::new(sample_rate, active.hop(), brightness, block_secs)),
+                _ => None
+            };
+            let mut piano = match mode {
+                Mode::Piano => Some(PianoKeys::new(sample_rate, active.hop(), brightness, block_secs)),
+                _ => None
+            };
+            let mut stereo = match mode {
+                Mode::Stereo => Some(StereoField::new(brightness, block_secs)),
+                _ => None
+            };
+            let mut chroma_colors = colors.clone();
+            let mut meter = loudness.map(|c| (audio::LoudnessMeter::new(sample_rate), LoudnessBrightness::new(c, block_secs)));
+            let fixed = vec![FIXED_RANGE; colors.len()];
+            let mut silence = audio::SilenceDetector::new(silence_config, block_secs);
+            let mut start_time: usize = 0;
             let mut pc = PulseCalc::new(brightness);
+            let pulse_period = ((PULSE_SECS / block_secs).round() as usize).max(2);
+            let mut last_phase = 0.0;
+            let mut beat_count = 0;
+            let mut dropped = 0;
+            let mut last_report = Instant::now();
+            let mut skipped = 0;
+            let mut last_skip_report = Instant::now();
             loop {
                 let ss = match active.next() {
                     Some(s) => s,
                     None => break
                 };
-                // fatch spectrogram using ffft
-                let res = if pulse && ss.is_no_sig() {
-                    //eprintln!("pulse and no sig are true");
+                // at most once a second, printing on every block would only add to the load
+                if active.dropped() != dropped && last_report.elapsed() >= Duration::from_secs(1) {
+                    let total = active.dropped();
+                    eprintln!("analysis falling behind, {} audio blocks dropped ({} in total)", total - dropped, total);
+                    dropped = total;
+                    last_report = Instant::now();
+                }
+                if pulse && silence.update(ss.rms_db()) {
                     start_time += 1;
-                    if start_time % 512 == 0 { pc.new_color(); }
-                    if start_time >= 512 {
-                        sender.try_send(vec![pc.ins_color(start_time,  512); led_num])
-                    } else {
-                        let (left, right) = ss.spectrogram(&mut planner);
-                        sender.try_send(compute_4_bins(left,right,led_num,invert,&colors,alg))
-                    }
                 } else {
-                    let (left, right) = ss.spectrogram(&mut planner);
                     start_time = 0;
-                    sender.try_send(compute_4_bins(left,right,led_num,invert,
-                        &colors,alg))
+                }
+                let res = if start_time > 0 {
+                    // pulse once a beat changing color every bar, or every few seconds without a tempo
+                    let tempo = tempo.as_mut().unwrap();
+                    tempo.process(0.0);
+                    let (period, time) = match tempo.period() {
+                        Some(p) => {
+                            let p = (p.round() as usize).max(2);
+                            let phase = tempo.phase();
+                            if phase < last_phase {
+                                beat_count += 1;
+                                if beat_count % 4 == 0 { pc.new_color(); }
+                            }
+                            last_phase = phase;
+                            // ins_color is brightest half way through the period
+                            (p, (phase * p as f32) as usize + p / 2)
+                        },
+                        None => {
+                            if start_time.is_multiple_of(pulse_period) { pc.new_color(); }
+                            (pulse_period, start_time)
+                        }
+                    };
+                    sender.try_send(vec![pc.ins_color(time, period); led_num])
+                } else {
+                    if let Some((onset_analyzer, onsets)) = onsets.as_mut() {
+                        let (left, right) = ss.spectrogram(onset_analyzer);
+                        beats.clear();
+                        onsets.process(left, right, &mut beats);
+                        if let Some(tempo) = tempo.as_mut() {
+                            tempo.process(onsets.flux().iter().sum());
+                        }
+                    }
+                    if let Some(flash) = flash.as_mut() {
+                        flash.update(&beats);
+                    }
+                    // fatch spectrogram using ffft
+                    let (left, right) = match history.as_mut() {
+                        Some(history) => ss.history_spectrogram(history, active.hop(), &mut analyzer),
+                        None => ss.spectrogram(&mut analyzer)
+                    };
+                    let mut vals = match (piano.as_mut(), stereo.as_mut()) {
+                        (Some(piano), _) => piano.update(&ss, led_num),
+                        (_, Some(stereo)) => stereo.update(ss.stereo_image(), led_num, invert),
+                        _ => {
+                            let (mut l_bins, mut r_bins) = (bands.levels(left), bands.levels(right));
+                            if let Some(env) = env.as_mut() {
+                                env.process(&mut l_bins, &mut r_bins);
+                            }
+                            let ranges = match agc.as_mut() {
+                                Some(agc) => {
+                                    agc.update(&l_bins, &r_bins);
+                                    agc.ranges()
+                                },
+                                None => &fixed
+                            };
+                            let band_colors = match chroma.as_mut() {
+                                Some(chroma) => {
+                                    let color = chroma.update(&ss);
+                                    for c in chroma_colors.iter_mut() {
+                                        *c = color;
+                                    }
+                                    &chroma_colors
+                                },
+                                None => &colors
+                            };
+                            let (mut vals, heights) = compute_bins(l_bins,r_bins,ranges,led_num,invert,band_colors,alg);
+                            if let Some(peaks) = peaks.as_mut() {
+                                peaks.draw(&mut vals, heights, block_secs);
+                            }
+                            vals
+                        }
+                    };
+                    if let Some(flash) = flash.as_mut() {
+                        flash.draw(&mut vals);
+                    }
+                    if let Some((meter, follower)) = meter.as_mut() {
+                        ss.loudness(meter, active.hop());
+                        let scale = follower.update(meter.momentary(), meter.integrated());
+                        for led in vals.iter_mut() {
+                            for v in led.iter_mut() {
+                                *v = (*v as f32 * scale) as u8;
+                            }
+                        }
+                    }
+                    sender.try_send(vals)
                 };
                 if let Err(e) = res {
                     match e {
-                        mpsc::TrySendError::Full(_) => eprintln!("led render taking to long"),
+                        mpsc::TrySendError::Full(_) => skipped += 1,
                         mpsc::TrySendError::Disconnected(_) => break
                     }
                 };
+                // short hops outpace the strip, so this too is only reported once a second
+                if skipped > 0 && last_skip_report.elapsed() >= Duration::from_secs(1) {
+                    eprintln!("led render taking too long, {} frames skipped", skipped);
+                    skipped = 0;
+                    last_skip_report = Instant::now();
+                }
             }
         });
         loop {
@@ -140,50 +479,96 @@ impl Controller {
         self 
     }
 }
+/// What the audio visualizer shows.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Mode {
+    /// Each band's level as a bar in the band's color.
+    Bands,
+    /// The band levels colored by the dominant pitch class, around the color wheel from C.
+    Chroma,
+    /// The keys of the notes sounding, laid out like the MIDI visualizer.
+    Piano,
+    /// The stereo image: placed by the balance, as wide as the side signal and 
+    /// colored by how alike the channels are.
+    Stereo
+}
 #[derive(Copy,Clone,Debug)]
 pub enum Algorithm {
     Linear,
     Quadratic
 }
-fn compute_4_bins(left: Vec<f32>, right: Vec<f32>, leds: usize, invert: bool, 
-                    colors: &[[u8; 4]; 4], alg: Algorithm)
-                    -> Vec<[u8; 4]> 
+/// Frequency bands and weightings resolved to the bins of one sample rate and FFT length.
+struct BandMap {
+    ranges: Vec<Range<usize>>,
+    weights: Vec<f32>,
+    filters: Option<audio::Filterbank>,
+    floor: Option<Vec<f32>>
+}
+impl BandMap {
+    // `edges` are ascending band edges in Hz, band i covering [edges[i], edges[i+1])
+    fn new(edges: &[f32], weighting: Weighting, sample_rate: usize, fft_len: usize) -> Self {
+        let bins = fft_len / 2 + 1;
+        let bin_hz = sample_rate as f32 / fft_len as f32;
+        let ranges = edges.windows(2).map(|e| audio::bin_range(e[0], e[1], sample_rate, fft_len)).collect();
+        let weights = (0..bins).map(|i| weighting.gain_db(i as f64 * bin_hz as f64)).collect();
+        BandMap { ranges, weights, filters: None, floor: None }
+    }
+    // one band per filter instead of per pair of edges
+    fn with_filterbank(filters: audio::Filterbank, weighting: Weighting, sample_rate: usize, fft_len: usize) -> Self {
+        let mut map = BandMap::new(&[], weighting, sample_rate, fft_len);
+        map.filters = Some(filters);
+        map
+    }
+    // a correction in dB per bin on top of the weighting
+    fn add_response(&mut self, correction: &[f32]) {
+        for (w, c) in self.weights.iter_mut().zip(correction) {
+            *w += c;
+        }
+    }
+    // a noise floor in dB per bin to subtract from the spectrum before measuring the bands
+    fn set_noise_floor(&mut self, floor: &[f32]) {
+        self.floor = Some(floor.iter().map(|n| 10f32.powf(n / 10.0)).collect());
+    }
+    // loudest weighted bin of each band, or the weighted power under each filter
+    fn levels(&self, spec: &[f32]) -> Vec<f32> {
+        let subtracted;
+        let spec = match &self.floor {
+            Some(floor) => {
+                // over-subtract to take the fluctuation of the noise with it, keeping 
+                // a little of the floor so the bins don't drop to -inf
+                subtracted = spec.iter().zip(floor).map(|(s, n)| {
+                    let p = 10f32.powf(s / 10.0);
+                    10.0 * (p - NOISE_OVERSUBTRACT * n).max(NOISE_RESIDUE * n).log10()
+                }).collect::<Vec<f32>>();
+                &subtracted[..]
+            },
+            None => spec
+        };
+        match &self.filters {
+            Some(filters) => {
+                let weighted: Vec<f32> = spec.iter().zip(&self.weights).map(|(s, w)| s + w).collect();
+                filters.process(&weighted)
+            },
+            None => self.ranges.iter().map(|range| max_weighted(&spec[range.clone()], &self.weights[range.clone()])).collect()
+        }
+    }
+}
+// `l_bins` and `r_bins` hold one level in dB per band from low to high, `ranges` 
+// the (floor, span) that maps each band onto the display. Also returns the height 
+// of the bars drawn from the start and the end of the strip.
+fn compute_bins(mut l_bins: Vec<f32>, mut r_bins: Vec<f32>, ranges: &[(f32, f32)], leds: usize, invert: bool, 
+                    colors: &[[u8; 4]], alg: Algorithm)
+                    -> (Vec<[u8; 4]>, [usize; 2])
 {
-    let n_windows = left.len() / 256;
-    let n_win = left.len() / 256;
-    // average channels
-    let mut l_avg = [0.0; 256];
-    let mut r_avg = [0.0; 256];
-    for i in 0..256 {
-        let mut l_sum = 0.0;
-        let mut r_sum = 0.0;
-        for n in 0..n_windows {
-            l_sum += left[i+n*256];
-            r_sum += right[i+n*256];
-        }
-        // average and apply weightins
-        l_avg[i] = (l_sum / n_win as f32) + WEIGHT[i];
-        r_avg[i] = (r_sum / n_win as f32) + WEIGHT[i];
-    }
-
-    let mut l_bins = [0.0; 4];
-    l_bins[0] = max(&l_avg[1..3]); // Subwoofer
-    l_bins[1] = max(&l_avg[3..6]); // Woofer
-    l_bins[2] = max(&l_avg[6..21]); // Midrange
-    l_bins[3] = max(&l_avg[21..256]); // Tweeter
-
-    let mut r_bins = [0.0; 4];
-    r_bins[0] = max(&r_avg[1..3]);
-    r_bins[1] = max(&r_avg[3..6]);
-    r_bins[2] = max(&r_avg[6..21]);
-    r_bins[3] = max(&r_avg[21..256]);
+    let n = colors.len();
 
     // scale to range of (-inf, 100] floating point number
     match alg { 
         Algorithm::Linear => { 
-            for i in 0..4 {
-                l_bins[i] = (l_bins[i]+ 40.0) * 2.000;
-                r_bins[i] = (r_bins[i] + 40.0) * 2.000;
+            for i in 0..n {
+                let (floor, span) = ranges[i];
+                l_bins[i] = (l_bins[i] - floor) * 100.0 / span;
+                r_bins[i] = (r_bins[i] - floor) * 100.0 / span;
                 if l_bins[i] > 100.0 {
                     l_bins[i] = 100.0;
                 } 
@@ -193,13 +578,14 @@ fn compute_4_bins(left: Vec<f32>, right: Vec<f32>, leds: usize, invert: bool,
             }
         },
         Algorithm::Quadratic => {
-            for i in 0..4 {
-                l_bins[i] = (l_bins[i]+ 40.0) / 5.000;
+            for i in 0..n {
+                let (floor, span) = ranges[i];
+                l_bins[i] = (l_bins[i] - floor) * 10.0 / span;
                 // only square if  over zero because negative will become positive otherwise
                  if l_bins[i] > 0.0 { 
                     l_bins[i] *= l_bins[i]; // square it 
                 }
-                r_bins[i] = (r_bins[i] + 40.0) / 5.000;
+                r_bins[i] = (r_bins[i] - floor) * 10.0 / span;
                 if r_bins[i] > 0.0 {
                     r_bins[i] *= r_bins[i]; // square it 
                 }
@@ -214,19 +600,19 @@ fn compute_4_bins(left: Vec<f32>, right: Vec<f32>, leds: usize, invert: bool,
     }
     //println!("{:?} {:?}", l_bins, r_bins);
 
-    let part_len = leds / 8; // length of each bin representing a color
+    let part_len = leds / (2 * n); // length of each bin representing a color
     let ratio = part_len as f32 / 100.0; // the ratio between the 0-100 range and the leds it
                                          // should effect
     let mut ret = vec![[0,0,0,0]; leds];
     // next section applies color values to left channel
     let bins = if invert { // invert the value if specified
-        r_bins
+        &r_bins
     } else {
-        l_bins
+        &l_bins
     };
 
     let mut l_sum: usize = 0;
-    for i in 0..4 {
+    for i in 0..n {
         let mut color_seg = (bins[i] * ratio).round() as usize;
         if color_seg < 1 {
             color_seg = 1;
@@ -239,14 +625,14 @@ fn compute_4_bins(left: Vec<f32>, right: Vec<f32>, leds: usize, invert: bool,
 
     // now repeat above for right channel
     let bins = if invert { // invert the value if specified
-        l_bins
+        &l_bins
     } else {
-        r_bins
+        &r_bins
     };
 
     let mut r_sum: usize = 0;
     let start = leds - 1;
-    for i in 0..4 {
+    for i in 0..n {
         let mut color_seg = (bins[i] * ratio).round() as usize;
         if color_seg < 1 {
             color_seg = 1;
@@ -257,12 +643,284 @@ fn compute_4_bins(left: Vec<f32>, right: Vec<f32>, leds: usize, invert: bool,
         r_sum += color_seg;
     }
     //println!("{:?}\n\n\n\n", ret);
-    ret
+    (ret, [l_sum, r_sum])
+}
+
+/// Settings for the peak-hold dot drawn above each channel's bar.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct PeakConfig {
+    pub hold: f32, // milliseconds the dot stays put after being pushed up
+    pub gravity: f32, // leds per second squared it accelerates at once released
+    pub color: [u8; 4]
+}
+impl Default for PeakConfig {
+    fn default() -> Self {
+        PeakConfig { hold: 1000.0, gravity: 150.0, color: [255, 255, 255, 0] }
+    }
 }
-fn max(s: &[f32]) -> f32 {
+// A falling dot, positions are in leds from the end of the strip its bar grows from.
+#[derive(Default)]
+struct PeakDot {
+    pos: f32,
+    vel: f32,
+    held: f32
+}
+impl PeakDot {
+    fn update(&mut self, height: usize, config: &PeakConfig, dt: f32) {
+        let height = height as f32;
+        if height >= self.pos {
+            self.pos = height;
+            self.vel = 0.0;
+            self.held = config.hold / 1000.0;
+        } else if self.held > 0.0 {
+            self.held -= dt;
+        } else {
+            self.vel += config.gravity * dt;
+            self.pos = (self.pos - self.vel * dt).max(height);
+        }
+    }
+}
+struct PeakHold {
+    config: PeakConfig,
+    dots: [PeakDot; 2]
+}
+impl PeakHold {
+    fn new(config: PeakConfig) -> Self {
+        PeakHold { config, dots: Default::default() }
+    }
+    // moves the dots for bars of `heights` and draws them onto `leds`, `dt` seconds after the last call
+    fn draw(&mut self, leds: &mut [[u8; 4]], heights: [usize; 2], dt: f32) {
+        let half = leds.len() / 2;
+        if half == 0 {
+            return;
+        }
+        for (dot, height) in self.dots.iter_mut().zip(heights.iter()) {
+            dot.update(*height, &self.config, dt);
+        }
+        let len = leds.len();
+        leds[(self.dots[0].pos.round() as usize).min(half - 1)] = self.config.color;
+        leds[len - 1 - (self.dots[1].pos.round() as usize).min(half - 1)] = self.config.color;
+    }
+}
+/// Picks a color for the dominant pitch class of each sample, smoothed so it 
+/// doesn't flicker between notes.
+struct ChromaColor {
+    analyzer: audio::Analyzer,
+    history: audio::History,
+    hop: usize,
+    chroma: audio::Chroma,
+    smoothed: [f32; 12],
+    smoothing: f32,
+    wheel: Vec<[u8; 4]>,
+    current: [u8; 4]
+}
+impl ChromaColor {
+    fn new(sample_rate: usize, hop: usize, brightness: f32, block_secs: f32) -> Self {
+        let wheel: Vec<[u8; 4]> = (0..12).map(|i| scale_color(hue_to_color(i as f32 * 30.0), brightness)).collect();
+        // far more than a sample, so the low octaves count too, and a window that 
+        // keeps a note from leaking into the neighbouring classes
+        ChromaColor {
+            analyzer: audio::Analyzer::new(CHROMA_FFT, audio::Window::Hann),
+            history: audio::History::new(CHROMA_FFT),
+            hop,
+            chroma: audio::Chroma::new(sample_rate, CHROMA_FFT),
+            smoothed: [0.0; 12],
+            smoothing: coefficient(150.0, block_secs),
+            current: wheel[0],
+            wheel
+        }
+    }
+    fn update(&mut self, ss: &audio::StereoSample) -> [u8; 4] {
+        let (left, right) = ss.history_spectrogram(&mut self.history, self.hop, &mut self.analyzer);
+        let chroma = self.chroma.process(left, right);
+        for (s, c) in self.smoothed.iter_mut().zip(chroma.iter()) {
+            *s = self.smoothing * *s + (1.0 - self.smoothing) * c;
+        }
+        // keep the last color through silence
+        if let Some(class) = audio::dominant_class(&self.smoothed) {
+            self.current = self.wheel[class];
+        }
+        self.current
+    }
+}
+
+/// Lights the keys of the notes sounding in the audio on the MIDI visualizer's 
+/// layout, from a constant-Q spectrum with one bin per piano key.
+struct PianoKeys {
+    cq: audio::ConstantQ,
+    hop: usize,
+    rc: RandomColor,
+    keys: Vec<(f32, [u8; 4])>, // brightness from 0 to 1 and color of each key
+    attack: f32,
+    release: f32,
+    brightness: f32
+}
+impl PianoKeys {
+    fn new(sample_rate: usize, hop: usize, brightness: f32, block_secs: f32) -> Self {
+        // A1 up to C8, or as far as the sample rate allows. Resolving the semitones of 
+        // the bottom octave would take a 32768 point FFT of each channel every hop at 
+        // 48kHz, too much for a Pi, so from A1 it is 16384 and the lowest keys stay dark.
+        let low = 27.5 * (PIANO_LOW_KEY as f32 / 12.0).exp2();
+        let keys = (0..88 - PIANO_LOW_KEY).take_while(|k| low * (*k as f32 / 12.0).exp2() < sample_rate as f32 * 0.45).count();
+        PianoKeys {
+            cq: audio::ConstantQ::new(sample_rate, low, keys, 12),
+            hop,
+            rc: RandomColor::new(),
+            keys: vec![(0.0, [0; 4]); keys],
+            attack: coefficient(20.0, block_secs),
+            release: coefficient(300.0, block_secs),
+            brightness
+        }
+    }
+    fn update(&mut self, ss: &audio::StereoSample, leds: usize) -> Vec<[u8; 4]> {
+        let (left, right) = ss.constant_q(&mut self.cq, self.hop);
+        let levels: Vec<f32> = left.iter().zip(right).map(|(l, r)| l.max(*r)).collect();
+        let strength = sounding_keys(&levels);
+        let mut vals = vec![[0; 4]; leds];
+        for (k, (key, target)) in self.keys.iter_mut().zip(strength).enumerate() {
+            if key.0 < 0.01 && target > 0.0 {
+                // a new note gets a new color, as each MIDI note does
+                let rand = self.rc.to_rgb_array();
+                key.1 = [rand[0] as u8, rand[1] as u8, rand[2] as u8, 0];
+            }
+            let c = if target > key.0 { self.attack } else { self.release };
+            key.0 = c * key.0 + (1.0 - c) * target;
+            if key.0 >= 0.01 {
+                let mut color = key.1;
+                for v in color.iter_mut() {
+                    *v = (*v as f32 * key.0 * self.brightness) as u8;
+                }
+                visualmidi::draw_key((21 + PIANO_LOW_KEY + k) as u8, color, &mut vals);
+            }
+        }
+        vals
+    }
+}
+// Takes the level in dB of each semitone and returns how strongly each one is 
+// sounding as a fundamental, from 0 to 1. Peaks a harmonic interval above a 
+// sounding note count as its overtones unless they are clearly louder.
+fn sounding_keys(levels: &[f32]) -> Vec<f32> {
+    let max = levels.iter().cloned().fold(KEY_FLOOR, f32::max);
+    let floor = (max - KEY_RANGE).max(KEY_FLOOR);
+    let mut strength = vec![0.0; levels.len()];
+    for k in 0..levels.len() {
+        let peak = (k == 0 || levels[k] >= levels[k - 1]) && (k + 1 == levels.len() || levels[k] > levels[k + 1]);
+        if !peak || levels[k] <= floor {
+            continue;
+        }
+        // octave, twelfth, two octaves and the major third above that
+        let overtone = [12, 19, 24, 28].iter().any(|i| {
+            k >= *i && strength[k - i] > 0.0 && levels[k] < levels[k - i] + 6.0
+        });
+        if !overtone {
+            strength[k] = (levels[k] - floor) / (max - floor);
+        }
+    }
+    strength
+}
+
+/// Draws an `audio::StereoImage` as a patch of light centred by the balance, 
+/// a single led for mono widening to the whole strip when the channels are 
+/// unrelated. Green for mono, through yellow, to red for channels that cancel.
+struct StereoField {
+    // smoothed mid and side powers, correlation and balance
+    mid: f32,
+    side: f32,
+    correlation: f32,
+    balance: f32,
+    smoothing: f32,
+    brightness: f32
+}
+impl StereoField {
+    fn new(brightness: f32, block_secs: f32) -> Self {
+        StereoField {
+            mid: 0.0,
+            side: 0.0,
+            correlation: 1.0,
+            balance: 0.0,
+            smoothing: coefficient(STEREO_SMOOTHING, block_secs),
+            brightness
+        }
+    }
+    fn update(&mut self, image: audio::StereoImage, leds: usize, invert: bool) -> Vec<[u8; 4]> {
+        let c = self.smoothing;
+        let smooth = |old: f32, new: f32| c * old + (1.0 - c) * new;
+        self.mid = smooth(self.mid, 10f32.powf(image.mid / 10.0));
+        self.side = smooth(self.side, 10f32.powf(image.side / 10.0));
+        self.correlation = smooth(self.correlation, image.correlation);
+        self.balance = smooth(self.balance, image.balance);
+        let mut vals = self.draw(leds);
+        if invert {
+            vals.reverse();
+        }
+        vals
+    }
+    // left at the start of the strip
+    fn draw(&self, leds: usize) -> Vec<[u8; 4]> {
+        let mut vals = vec![[0; 4]; leds];
+        let (floor, span) = STEREO_RANGE;
+        let level = ((10.0 * (self.mid + self.side).log10() - floor) / span).min(1.0);
+        if level.is_nan() || level <= 0.0 {
+            return vals;
+        }
+        // the side is 0 for mono, as strong as the mid for unrelated channels and all there is when they cancel
+        let width = (2.0 * self.side / (self.mid + self.side)).min(1.0);
+        let half = width * leds as f32 / 2.0;
+        let centre = (leds as f32 - 1.0) / 2.0 * (1.0 + self.balance);
+        let mut color = hue_to_color(60.0 * (1.0 + self.correlation));
+        for v in color.iter_mut() {
+            *v = (*v as f32 * level * self.brightness) as u8;
+        }
+        for (i, led) in vals.iter_mut().enumerate() {
+            // fade out over a led past the edges
+            let fade = 1.0 - ((i as f32 - centre).abs() - half).max(0.0);
+            if fade > 0.0 {
+                for j in 0..3 {
+                    led[j] = (color[j] as f32 * fade) as u8;
+                }
+            }
+        }
+        vals
+    }
+}
+
+/// Flashes the background on kicks found by an `audio::OnsetDetector`.
+struct BeatFlash {
+    color: [u8; 4],
+    level: f32, // 0 to 1, how bright the flash currently is
+    decay: f32
+}
+impl BeatFlash {
+    fn new(color: [u8; 4], block_secs: f32) -> Self {
+        BeatFlash {
+            color,
+            level: 0.0,
+            decay: coefficient(200.0, block_secs)
+        }
+    }
+    fn update(&mut self, beats: &[audio::Beat]) {
+        self.level *= self.decay;
+        for beat in beats.iter().filter(|b| b.band == audio::BeatBand::Kick) {
+            // even a marginal kick should be visible
+            self.level = self.level.max(0.25 + 0.75 * beat.confidence);
+        }
+    }
+    fn draw(&self, leds: &mut [[u8; 4]]) {
+        if self.level < 0.01 {
+            return;
+        }
+        for led in leds.iter_mut().filter(|l| **l == [0; 4]) {
+            for (l, c) in led.iter_mut().zip(self.color.iter()) {
+                *l = (*c as f32 * self.level) as u8;
+            }
+        }
+    }
+}
+
+fn max_weighted(s: &[f32], weights: &[f32]) -> f32 {
     let mut ret = std::f32::NEG_INFINITY;
-    for i in s {
-        if *i > ret { ret = *i }
+    for (i, w) in s.iter().zip(weights) {
+        if *i + *w > ret { ret = *i + *w }
     } 
     ret
 }
@@ -367,10 +1025,238 @@ pub fn calc_nths(rands: [f32; 3]) -> [f32; 4] {
     [pow[0] - 1.0, pow[1] - 1.0, pow[2] - 1.0, 0.0]
 }
 
+// The shortest FFT of at least 256 points that gives every band bins of its own, 
+// or None if even `MAX_BAND_FFT` points don't. Narrow bands sharing a bin would 
+// only show the same level.
+fn band_fft_len(edges: &[f32], sample_rate: usize) -> Option<usize> {
+    let distinct = |len: usize| {
+        let ranges = BandMap::new(edges, Weighting::Flat, sample_rate, len).ranges;
+        ranges.iter().all(|r| !r.is_empty()) && ranges.windows(2).all(|r| r[0].end <= r[1].start)
+    };
+    let mut len = 256;
+    while len < MAX_BAND_FFT && !distinct(len) {
+        len *= 2;
+    }
+    if distinct(len) { Some(len) } else { None }
+}
+/// The most bands `Band::log_spaced` can split `low` to `high` Hz into at 
+/// `sample_rate` that still each get bins of their own.
+pub fn max_bands(low: f32, high: f32, sample_rate: usize) -> usize {
+    let resolved = |n| {
+        let mut edges = vec![low];
+        edges.extend(Band::log_spaced(n, low, high).iter().map(|b| b.upper));
+        band_fft_len(&edges, sample_rate).is_some()
+    };
+    (1..).take_while(|n| resolved(*n)).last().unwrap_or(0)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_band_map_reference_rate() {
+        let bands = BandMap::new(&BAND_EDGES, Weighting::Flat, 48000, 256);
+        assert_eq!(bands.ranges, vec![1..3, 3..6, 6..21, 21..129]);
+        assert_eq!(bands.weights, vec![0.0; 129]);
+    }
+
+    #[test]
+    fn test_band_map_other_rates() {
+        let bands = BandMap::new(&BAND_EDGES, Weighting::A, 96000, 256);
+        assert_eq!(bands.ranges, vec![1..2, 2..3, 3..11, 11..129]);
+        // weights follow the bin frequencies, 375Hz per bin
+        assert_eq!(bands.weights[8], Weighting::A.gain_db(3000.0));
+        let bands = BandMap::new(&BAND_EDGES, Weighting::Itu468, 44100, 1024);
+        assert_eq!(bands.ranges, vec![4..12, 12..24, 24..89, 89..513]);
+        assert_eq!(bands.weights.len(), 513);
+        // bands narrower than a bin still get one
+        let bands = BandMap::new(&BAND_EDGES, Weighting::C, 96000, 64);
+        assert_eq!(bands.ranges, vec![1..2, 1..2, 1..3, 3..33]);
+    }
+
+    #[test]
+    fn test_band_map_filterbank() {
+        let filters = audio::Filterbank::new(audio::FilterScale::Mel, 8, 150.0, 16000.0, 48000, 1024);
+        let bands = BandMap::with_filterbank(filters, Weighting::Flat, 48000, 1024);
+        // a flat spectrum reads the same in every band whatever its width
+        let levels = bands.levels(&[-30.0; 513]);
+        assert_eq!(levels.len(), 8);
+        assert!(levels.iter().all(|l| (l + 30.0).abs() < 1e-3), "{:?}", levels);
+    }
+
+    #[test]
+    fn test_band_map_noise_floor() {
+        let mut bands = BandMap::new(&BAND_EDGES, Weighting::Flat, 48000, 256);
+        bands.set_noise_floor(&[-60.0; 129]);
+        // the noise itself is pushed 20dB below its floor
+        let levels = bands.levels(&[-60.0; 129]);
+        assert!(levels.iter().all(|l| (l + 80.0).abs() < 1e-3), "{:?}", levels);
+        // while music well above it barely changes
+        let levels = bands.levels(&[-20.0; 129]);
+        assert!(levels.iter().all(|l| (l + 20.0).abs() < 1e-3), "{:?}", levels);
+    }
+
+    #[test]
+    fn test_band_map_recorded_noise_floor() {
+        use crate::audio::{PendingProducer, Signal, SignalGenerator};
+        use crate::calibration;
+        let noise = || SignalGenerator::new(Signal::WhiteNoise { amp: 0.5 }, Signal::WhiteNoise { amp: 0.5 }, 48000, false)
+            .set_duration(2.0);
+        let profile = calibration::record(PendingProducer::new(noise(), 1024), 2.0, 1024).unwrap();
+        // bands of one bin each, read as the display does with a shorter rectangular FFT
+        let edges: Vec<f32> = (4..124).map(|k| (k as f32 + 0.5) * 187.5).collect();
+        let mut bands = BandMap::new(&edges, Weighting::Flat, 48000, 256);
+        bands.set_noise_floor(&profile.noise_floor(48000, 256, audio::Window::Rectangular));
+        let mut analyzer = audio::Analyzer::new(256, audio::Window::Rectangular);
+        let mut levels = Vec::new();
+        for ss in PendingProducer::new(noise(), 1024).activate().unwrap() {
+            let (left, _) = ss.spectrogram(&mut analyzer);
+            levels.extend(bands.levels(left));
+        }
+        // the noise's own power in those bins, a block at a time
+        let (mut power, mut count) = (0.0, 0);
+        for ss in PendingProducer::new(noise(), 256).activate().unwrap() {
+            let (left, _) = ss.spectrogram(&mut analyzer);
+            power += left[5..124].iter().map(|l| 10f32.powf(l / 10.0)).sum::<f32>();
+            count += 119;
+        }
+        // nearly all of it is left at the residue, 20dB below the noise
+        levels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = levels[levels.len() / 2];
+        let expected = 10.0 * (power / count as f32).log10() + 10.0 * NOISE_RESIDUE.log10();
+        assert!((median - expected).abs() < 1.0, "{} {}", median, expected);
+    }
+
+    #[test]
+    fn test_stereo_field() {
+        let field = |mid, side, correlation, balance| StereoField { mid, side, correlation, balance, ..StereoField::new(1.0, 0.01) };
+        let lit = |vals: &[[u8; 4]]| vals.iter().enumerate().filter(|(_, v)| v[..3] != [0, 0, 0]).map(|(i, _)| i).collect::<Vec<_>>();
+        // loud mono in the middle is a single green led
+        let vals = field(1.0, 0.0, 1.0, 0.0).draw(21);
+        assert_eq!(lit(&vals), vec![10]);
+        assert_eq!(vals[10], [0, 255, 0, 0]);
+        // panned hard left it moves to the start
+        assert_eq!(lit(&field(1.0, 0.0, 1.0, -1.0).draw(21)), vec![0]);
+        // unrelated channels fill the strip in yellow
+        let vals = field(0.5, 0.5, 0.0, 0.0).draw(21);
+        assert_eq!(lit(&vals).len(), 21);
+        assert_eq!(vals[0], [255, 255, 0, 0]);
+        // and silence leaves it dark
+        assert!(lit(&field(0.0, 0.0, 1.0, 0.0).draw(21)).is_empty());
+    }
+
+    #[test]
+    fn test_sounding_keys_skips_overtones() {
+        let mut levels = vec![-90.0; 88];
+        // A2 with its next three harmonics, and a C#5 far louder than its fifth would be
+        levels[24] = -10.0;
+        levels[36] = -14.0;
+        levels[43] = -18.0;
+        levels[48] = -20.0;
+        levels[52] = -2.0;
+        let keys = sounding_keys(&levels);
+        let lit: Vec<usize> = (0..88).filter(|k| keys[*k] > 0.0).collect();
+        assert_eq!(lit, vec![24, 52]);
+        assert_eq!(keys[52], 1.0);
+        assert!(sounding_keys(&[-90.0; 88]).iter().all(|k| *k == 0.0));
+    }
+
+    #[test]
+    fn test_weighting_reference_points() {
+        // (weighting, Hz, dB) from the published tables
+        let points = [(Weighting::A, 1000.0, 0.0), (Weighting::A, 100.0, -19.1), (Weighting::A, 10000.0, -2.5),
+            (Weighting::C, 1000.0, 0.0), (Weighting::C, 31.5, -3.0), (Weighting::C, 10000.0, -4.4),
+            (Weighting::Itu468, 1000.0, 0.0), (Weighting::Itu468, 100.0, -19.8), (Weighting::Itu468, 6300.0, 12.2),
+            (Weighting::Flat, 50.0, 0.0)];
+        for (w, f, db) in points.iter() {
+            let got = w.gain_db(*f);
+            assert!((got - db).abs() < 0.1, "{:?} at {}Hz: {} vs {}", w, f, got, db);
+        }
+    }
+
+    #[test]
+    fn test_band_fft_len() {
+        let edges = |n| {
+            let mut edges = vec![150.0];
+            edges.extend(Band::log_spaced(n, 150.0, 16000.0).iter().map(|b| b.upper));
+            edges
+        };
+        // the default bands are resolved by the shortest FFT
+        assert_eq!(band_fft_len(&BAND_EDGES, 48000), Some(256));
+        // 16 bands from 150Hz need the whole sample, where each gets its own bins
+        let sixteen = edges(16);
+        assert_eq!(band_fft_len(&sixteen, 48000), Some(1024));
+        let ranges = BandMap::new(&sixteen, Weighting::Flat, 48000, 1024).ranges;
+        assert_eq!(ranges[..4], [4..5, 5..6, 6..8, 8..11]);
+        assert!(ranges.windows(2).all(|r| r[0].end == r[1].start));
+        // 32 need more frames than a sample holds
+        let len = band_fft_len(&edges(32), 48000).unwrap();
+        assert!(len > 1024);
+        let ranges = BandMap::new(&edges(32), Weighting::Flat, 48000, len).ranges;
+        assert_eq!(ranges.len(), 32);
+        assert!(ranges.iter().all(|r| !r.is_empty()));
+        assert!(ranges.windows(2).all(|r| r[0].end <= r[1].start));
+        assert!(max_bands(150.0, 16000.0, 48000) >= 32);
+        assert_eq!(band_fft_len(&edges(max_bands(150.0, 16000.0, 48000) + 1), 48000), None);
+    }
+
+    #[test]
+    fn test_log_spaced_bands() {
+        let bands = Band::log_spaced(4, 100.0, 10000.0);
+        assert_eq!(bands.len(), 4);
+        assert!((bands[0].upper - 316.22775).abs() < 0.01);
+        assert!((bands[1].upper - 1000.0).abs() < 0.1);
+        assert_eq!(bands[3].upper, f32::INFINITY);
+        assert_eq!(bands[0].color, [0, 0, 255, 0]);
+        assert_eq!(bands[3].color, [255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_compute_bins_splits_strip() {
+        let edges = [150.0, 1000.0, f32::INFINITY];
+        let bands = BandMap::new(&edges, Weighting::Flat, 48000, 256);
+        let spec = vec![-200.0; 129]; // silence, every band gets its minimum single led
+        let colors = [[1, 0, 0, 0], [2, 0, 0, 0]];
+        let ranges = [FIXED_RANGE; 2];
+        let (ret, heights) = compute_bins(bands.levels(&spec), bands.levels(&spec), &ranges, 8, false, &colors, Algorithm::Linear);
+        assert_eq!(ret, vec![[1, 0, 0, 0], [2, 0, 0, 0], [0; 4], [0; 4], [0; 4], [0; 4], [2, 0, 0, 0], [1, 0, 0, 0]]);
+        assert_eq!(heights, [2, 2]);
+        // full scale fills each band's share, 8 leds / (2 sides * 2 bands)
+        let spec = vec![100.0; 129];
+        let (ret, _) = compute_bins(bands.levels(&spec), bands.levels(&spec), &ranges, 8, false, &colors, Algorithm::Linear);
+        assert_eq!(ret, vec![[1, 0, 0, 0], [1, 0, 0, 0], [2, 0, 0, 0], [2, 0, 0, 0], 
+            [2, 0, 0, 0], [2, 0, 0, 0], [1, 0, 0, 0], [1, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_peak_hold_and_fall() {
+        let config = PeakConfig { hold: 100.0, gravity: 100.0, color: [9, 9, 9, 0] };
+        let mut peaks = PeakHold::new(config);
+        let mut leds = [[0; 4]; 40];
+        peaks.draw(&mut leds, [10, 3], 0.05);
+        assert_eq!(leds[10], [9, 9, 9, 0]);
+        assert_eq!(leds[36], [9, 9, 9, 0]);
+        // held for 100ms
+        peaks.draw(&mut [[0; 4]; 40], [2, 2], 0.05);
+        peaks.draw(&mut [[0; 4]; 40], [2, 2], 0.05);
+        assert_eq!(peaks.dots[0].pos, 10.0);
+        // then accelerates downwards until it lands on the bar
+        let mut last_step = 0.0;
+        for _ in 0..10 {
+            let before = peaks.dots[0].pos;
+            peaks.draw(&mut [[0; 4]; 40], [2, 2], 0.05);
+            let step = before - peaks.dots[0].pos;
+            assert!(step >= last_step || peaks.dots[0].pos == 2.0);
+            last_step = step;
+        }
+        assert_eq!(peaks.dots[0].pos, 2.0);
+        // a taller bar pushes it straight back up
+        peaks.draw(&mut leds, [30, 0], 0.05);
+        assert_eq!(peaks.dots[0].pos, 30.0);
+        assert_eq!(leds[19], [9, 9, 9, 0]); // but it stays on its half of the strip
+    }
+
     #[test]
     fn test_calc_nths_50_100_150() {
         let res = calc_nths([50.0,100.0,150.0]);
@@ -1,5 +1,7 @@
 pub mod led;
 pub mod audio;
+pub mod dynamics;
 pub mod midi;
 pub mod visualmidi;
+pub mod calibration;
 
@@ -1,32 +1,214 @@
 use clap;
 use spectrum::audio;
+use spectrum::calibration;
+use spectrum::dynamics;
 use spectrum::led;
 use spectrum::midi;
 use spectrum::visualmidi::{MidiCon,MidiRenderer};
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 fn main2() {
     let jack_client = midi::new_jack().unwrap();
     let mut midi_con = MidiCon::new(18, 288, false);
     midi_con.set_verbose(true);
     midi_con.display(jack_client);
 }
+// Where the audio comes from.
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Source<'a> {
+    Jack,
+    Wav(&'a str),
+    Signal(&'a str),
+    Pcm(&'a str)
+}
+impl<'a> Source<'a> {
+    fn from_args(args: &'a clap::ArgMatches) -> Self {
+        if let Some(path) = args.value_of("wav") {
+            Source::Wav(path)
+        } else if let Some(spec) = args.value_of("signal") {
+            Source::Signal(spec)
+        } else if let Some(path) = args.value_of("pcm") {
+            Source::Pcm(path)
+        } else {
+            Source::Jack
+        }
+    }
+}
+// What to do with the input.
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Mode<'a> {
+    // note events from a JACK MIDI port on the MIDI visualizer
+    Midi,
+    Bands,
+    Pitch,
+    CalibrateNoise(&'a str),
+    CalibrateResponse(&'a str)
+}
+impl<'a> Mode<'a> {
+    fn from_args(args: &'a clap::ArgMatches) -> Self {
+        if args.is_present("midi") {
+            Mode::Midi
+        } else if let Some(path) = args.value_of("calibrate_noise") {
+            Mode::CalibrateNoise(path)
+        } else if let Some(path) = args.value_of("calibrate_response") {
+            Mode::CalibrateResponse(path)
+        } else if args.is_present("pitch") {
+            Mode::Pitch
+        } else {
+            Mode::Bands
+        }
+    }
+}
 fn main() {
-    main2();
     let args = parse_args();
+    let source = Source::from_args(&args);
+    let mode = Mode::from_args(&args);
+    if mode == Mode::Midi {
+        main2();
+        return;
+    }
+    let hop = usize::from_str(args.value_of("hop").unwrap()).unwrap();
+    match source {
+        Source::Wav(path) => {
+            let pp = audio::PendingProducer::new_wav(path, 1024, !args.is_present("fast")).unwrap()
+                .set_hop(hop);
+            display(&args, source, mode, pp);
+        },
+        Source::Signal(spec) => {
+            let mut specs = spec.splitn(2, ',').map(|s| audio::Signal::from_str(s).unwrap());
+            let left = specs.next().unwrap();
+            let right = specs.next().unwrap_or(left);
+            let rate = usize::from_str(args.value_of("rate").unwrap()).unwrap();
+            let pp = audio::PendingProducer::new(audio::SignalGenerator::new(left, right, rate, true), 1024)
+                .set_hop(hop);
+            display(&args, source, mode, pp);
+        },
+        Source::Pcm(path) => {
+            let format = audio::SampleFormat::from_str(args.value_of("format").unwrap()).unwrap();
+            let channels = usize::from_str(args.value_of("channels").unwrap()).unwrap();
+            let rate = usize::from_str(args.value_of("rate").unwrap()).unwrap();
+            let stream = if path == "-" {
+                audio::PcmStream::stdin(format, channels, rate)
+            } else {
+                audio::PcmStream::open(path, format, channels, rate).unwrap()
+            };
+            display(&args, source, mode, audio::PendingProducer::new(stream, 1024).set_hop(hop));
+        },
+        Source::Jack => {
+            let pp = audio::PendingProducer::new_jack(1024).unwrap().set_hop(hop);
+            display(&args, source, mode, pp);
+        }
+    }
+}
+
+// show the audio as band levels, or as the notes of its pitch on the MIDI visualizer,
+// or record it as a calibration for later runs
+fn display<S: audio::InactiveSource + 'static>(args: &clap::ArgMatches, source: Source, mode: Mode, pp: audio::PendingProducer<S>) {
+    let secs = f32::from_str(args.value_of("calibrate_secs").unwrap()).unwrap();
+    match mode {
+        Mode::CalibrateNoise(path) => {
+            eprintln!("recording the noise floor for {}s, keep the room quiet", secs);
+            calibration::record(pp, secs, 1024).unwrap().save(path).unwrap();
+        },
+        Mode::CalibrateResponse(path) => {
+            // a live input hears the room, so fill it with pink noise while recording
+            let _player = if source == Source::Jack {
+                let player = audio::SignalPlayer::new_jack(audio::Signal::PinkNoise { amp: 0.5 }).unwrap();
+                thread::sleep(Duration::from_millis(500)); // let the noise reach the microphone
+                Some(player)
+            } else {
+                None
+            };
+            eprintln!("recording the response to pink noise for {}s", secs);
+            let measured = calibration::record(pp, secs, 1024).unwrap();
+            calibration::response(&measured).unwrap().save(path).unwrap();
+        },
+        Mode::Pitch => {
+            let mut midi_con = MidiCon::new(18, 288, false);
+            midi_con.set_verbose(true);
+            midi_con.display(midi::PitchSource::new(pp)).unwrap();
+        },
+        Mode::Bands => {
+            controller(args).display(pp);
+        },
+        Mode::Midi => unreachable!("the midi mode has no audio source")
+    }
+}
+fn controller(args: &clap::ArgMatches) -> led::Controller {
     let brightness = 
         f32::from_str(args.value_of("brightness").unwrap()).unwrap(); // neither unwrap should ever fail
-    let pp = audio::PendingProducer::new_jack(1024).unwrap();
     let mut con = led::Controller::new(18, 300, false, brightness, true);
     match args.value_of("scaling_alg").unwrap() {
         "linear" => con = con.set_alg(led::Algorithm::Linear),
         "quadratic" => con = con.set_alg(led::Algorithm::Quadratic),
         _ => panic!("Unimplemented value for scaling_alg")
     }
-    con.display(pp);        
+    if !args.is_present("fixed_gain") {
+        con = con.set_agc(Some(dynamics::AgcConfig::default()));
+    }
+    if !args.is_present("no_envelope") {
+        let attack = f32::from_str(args.value_of("attack").unwrap()).unwrap();
+        let release = f32::from_str(args.value_of("release").unwrap()).unwrap();
+        con = con.set_envelope(Some((attack, release)));
+    }
+    if let Some(hold) = args.value_of("peak_hold") {
+        let peak = led::PeakConfig {
+            hold: f32::from_str(hold).unwrap(),
+            color: parse_color(args.value_of("peak_color").unwrap()).unwrap(),
+            ..led::PeakConfig::default()
+        };
+        con = con.set_peak(Some(peak));
+    }
+    con = con.set_silence(audio::SilenceConfig {
+        threshold: f32::from_str(args.value_of("silence_threshold").unwrap()).unwrap(),
+        enter: f32::from_str(args.value_of("silence_enter").unwrap()).unwrap(),
+        exit: f32::from_str(args.value_of("silence_exit").unwrap()).unwrap()
+    });
+    if let Some(path) = args.value_of("noise_floor") {
+        con = con.set_noise_floor(Some(calibration::Profile::load(path).unwrap()));
+    }
+    if let Some(path) = args.value_of("response") {
+        con = con.set_response(Some(calibration::Profile::load(path).unwrap()));
+    }
+    if args.is_present("loudness") {
+        con = con.set_loudness(Some(dynamics::LoudnessConfig::default()));
+    }
+    if let Some(color) = args.value_of("beat_flash") {
+        con = con.set_beat_flash(Some(parse_color(color).unwrap()));
+    }
+    match args.value_of("mode").unwrap() {
+        "bands" => con = con.set_mode(led::Mode::Bands),
+        "chroma" => con = con.set_mode(led::Mode::Chroma),
+        "piano" => con = con.set_mode(led::Mode::Piano),
+        "stereo" => con = con.set_mode(led::Mode::Stereo),
+        _ => panic!("Unimplemented value for mode")
+    }
+    match args.value_of("weighting").unwrap() {
+        "flat" => con = con.set_weighting(led::Weighting::Flat),
+        "a" => con = con.set_weighting(led::Weighting::A),
+        "c" => con = con.set_weighting(led::Weighting::C),
+        "468" => con = con.set_weighting(led::Weighting::Itu468),
+        _ => panic!("Unimplemented value for weighting")
+    }
+    con = con.set_window(audio::Window::from_str(args.value_of("window").unwrap()).unwrap());
+    let low = f32::from_str(args.value_of("band_low").unwrap()).unwrap();
+    let high = f32::from_str(args.value_of("band_high").unwrap()).unwrap();
+    if let Some(n) = args.value_of("bands") {
+        let n = usize::from_str(n).unwrap();
+        con = con.set_bands(low, led::Band::log_spaced(n, low, high));
+    }
+    match args.value_of("filterbank") {
+        Some("mel") => con = con.set_filterbank(Some((audio::FilterScale::Mel, low, high))),
+        Some("log") => con = con.set_filterbank(Some((audio::FilterScale::Log, low, high))),
+        Some(_) => panic!("Unimplemented value for filterbank"),
+        None => ()
+    }
+    con
 }
 
 fn parse_args<'a>() -> clap::ArgMatches<'a> {
-    clap::App::new("spectrum")
+    let args = clap::App::new("spectrum")
         .author("curtismaves@gmail.com")
         .version("0.1.0")
         .about("This program takes ")
@@ -59,5 +241,334 @@ fn parse_args<'a>() -> clap::ArgMatches<'a> {
                 .possible_values(&["linear", "quadratic"])
                 .default_value("quadratic")
         )
-        .get_matches()
+        .arg(
+            clap::Arg::with_name("fixed_gain")
+                .long("fixed-gain")
+                .help("Map -40dB..10dB onto the strip instead of adapting the gain to the input level.")
+        )
+        .arg(
+            clap::Arg::with_name("attack")
+                .long("attack")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("5")
+                .help("Attack time of the per-band envelope follower in milliseconds.")
+                .validator(validate_ms)
+        )
+        .arg(
+            clap::Arg::with_name("release")
+                .long("release")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("250")
+                .help("Release time of the per-band envelope follower in milliseconds.")
+                .validator(validate_ms)
+        )
+        .arg(
+            clap::Arg::with_name("no_envelope")
+                .long("no-envelope")
+                .conflicts_with_all(&["attack", "release"])
+                .help("Show raw band levels without envelope smoothing.")
+        )
+        .arg(
+            clap::Arg::with_name("peak_hold")
+                .long("peak-hold")
+                .takes_value(true)
+                .value_name("MS")
+                .help("Draw a peak-hold dot that stays MS milliseconds above each channel's bar before falling.")
+                .validator(validate_ms)
+        )
+        .arg(
+            clap::Arg::with_name("peak_color")
+                .long("peak-color")
+                .takes_value(true)
+                .value_name("RRGGBB")
+                .default_value("ffffff")
+                .help("Color of the peak-hold dot as a hex triplet.")
+                .validator(|s| parse_color(&s).map(|_| ()))
+        )
+        .arg(
+            clap::Arg::with_name("silence_threshold")
+                .long("silence-threshold")
+                .takes_value(true)
+                .value_name("DBFS")
+                .default_value("-60")
+                .allow_hyphen_values(true)
+                .help("Input with an rms level below this counts as silence and brings up the idle pulse.")
+                .validator(|s| match f32::from_str(&s) {
+                    Ok(db) if db <= 0.0 => Ok(()),
+                    _ => Err("Threshold should be a level in dBFS of at most 0".to_string())
+                })
+        )
+        .arg(
+            clap::Arg::with_name("silence_enter")
+                .long("silence-enter")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("3000")
+                .help("How long the input must stay below the silence threshold before the pulse starts.")
+                .validator(validate_ms)
+        )
+        .arg(
+            clap::Arg::with_name("silence_exit")
+                .long("silence-exit")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("100")
+                .help("How long the input must stay above the silence threshold before the pulse stops.")
+                .validator(validate_ms)
+        )
+        .arg(
+            clap::Arg::with_name("calibrate_noise")
+                .long("calibrate-noise")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Record the ambient input with no music playing and save its spectrum as a noise floor, instead of driving the strip.")
+        )
+        .arg(
+            clap::Arg::with_name("calibrate_response")
+                .long("calibrate-response")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Play pink noise through JACK, record how the room and microphone color it and save the correction, instead of driving the strip.")
+                .conflicts_with("calibrate_noise")
+        )
+        .arg(
+            clap::Arg::with_name("calibrate_secs")
+                .long("calibrate-secs")
+                .takes_value(true)
+                .value_name("SECS")
+                .default_value("5")
+                .help("How long to record for calibration.")
+                .validator(|s| match f32::from_str(&s) {
+                    Ok(f) if f > 0.0 && f.is_finite() => Ok(()),
+                    _ => Err("Duration should be a positive number of seconds".to_string())
+                })
+        )
+        .arg(
+            clap::Arg::with_name("noise_floor")
+                .long("noise-floor")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Subtract a noise floor recorded with --calibrate-noise from the spectrum.")
+                .conflicts_with("calibrate_noise")
+        )
+        .arg(
+            clap::Arg::with_name("response")
+                .long("response")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Correct the spectrum with a response recorded by --calibrate-response, on top of the weighting. Use --weighting flat for the correction alone.")
+                .conflicts_with("calibrate_response")
+        )
+        .arg(
+            clap::Arg::with_name("loudness")
+                .long("loudness")
+                .help("Dim the strip in passages quieter than the music so far and brighten it in louder ones.")
+        )
+        .arg(
+            clap::Arg::with_name("beat_flash")
+                .long("beat-flash")
+                .takes_value(true)
+                .value_name("RRGGBB")
+                .help("Flash the unlit LEDs in this color on every detected kick drum.")
+                .validator(|s| parse_color(&s).map(|_| ()))
+        )
+        .arg(
+            clap::Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .value_name("MODE")
+                .help("Sets what the strip shows: band levels in band colors, band levels colored by the dominant pitch class, the keys being played, or the stereo image.")
+                .possible_values(&["bands", "chroma", "piano", "stereo"])
+                .default_value("bands")
+        )
+        .arg(
+            clap::Arg::with_name("weighting")
+                .long("weighting")
+                .takes_value(true)
+                .value_name("CURVE")
+                .help("Sets the frequency weighting curve applied to the spectrum.")
+                .possible_values(&["flat", "a", "c", "468"])
+                .default_value("468")
+        )
+        .arg(
+            clap::Arg::with_name("window")
+                .long("window")
+                .takes_value(true)
+                .value_name("WINDOW")
+                .help("Sets the window function applied before the FFT.")
+                .possible_values(&["rectangular", "hann", "hamming", "blackman-harris", "flat-top"])
+                .default_value("rectangular")
+        )
+        .arg(
+            clap::Arg::with_name("bands")
+                .long("bands")
+                .takes_value(true)
+                .value_name("N")
+                .help("Split the spectrum into N log spaced bands instead of the default four.")
+                .validator(|s| match usize::from_str(&s) {
+                    Ok(n) if (1..=150).contains(&n) => Ok(()),
+                    _ => Err("Bands should be an integer between 1 and 150".to_string())
+                })
+        )
+        .arg(
+            clap::Arg::with_name("filterbank")
+                .long("filterbank")
+                .takes_value(true)
+                .value_name("SCALE")
+                .help("Measure the bands with overlapping triangular filters spaced on this scale from --band-low to --band-high.")
+                .possible_values(&["mel", "log"])
+        )
+        .arg(
+            clap::Arg::with_name("band_low")
+                .long("band-low")
+                .takes_value(true)
+                .value_name("HZ")
+                .default_value("150")
+                .help("Lowest frequency split into --bands or covered by --filterbank.")
+                .validator(validate_hz)
+        )
+        .arg(
+            clap::Arg::with_name("band_high")
+                .long("band-high")
+                .takes_value(true)
+                .value_name("HZ")
+                .default_value("16000")
+                .help("Highest frequency split into --bands or covered by --filterbank.")
+                .validator(validate_hz)
+        )
+        .arg(
+            clap::Arg::with_name("hop")
+                .long("hop")
+                .takes_value(true)
+                .value_name("FRAMES")
+                .default_value("1024")
+                .help("Frames between successive 1024 frame analysis windows. 256 gives 75% overlap.")
+                .validator(|s| match usize::from_str(&s) {
+                    Ok(h) if (128..=1024).contains(&h) => Ok(()),
+                    _ => Err("Hop should be an integer between 128 and 1024".to_string())
+                })
+        )
+        .arg(
+            clap::Arg::with_name("wav")
+                .short("w")
+                .long("wav")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read audio from a WAV file instead of JACK.")
+        )
+        .arg(
+            clap::Arg::with_name("signal")
+                .long("signal")
+                .takes_value(true)
+                .value_name("LEFT[,RIGHT]")
+                .conflicts_with("wav")
+                .help("Generate a test signal instead of reading JACK: sine:FREQ, sweep:START:END:SECS, \
+                    white, pink, impulse:SECS or silence, each with an optional :AMP suffix.")
+                .validator(|s| {
+                    for spec in s.splitn(2, ',') {
+                        audio::Signal::from_str(spec)?;
+                    }
+                    Ok(())
+                })
+        )
+        .arg(
+            clap::Arg::with_name("pcm")
+                .long("pcm")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["wav", "signal"])
+                .help("Read raw interleaved PCM from a file or named pipe instead of JACK, or - for stdin.")
+        )
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["u8", "s16", "s24", "s32", "f32", "f64"])
+                .default_value("f32")
+                .help("Little-endian sample format of --pcm input.")
+        )
+        .arg(
+            clap::Arg::with_name("channels")
+                .long("channels")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("2")
+                .help("Number of interleaved channels in --pcm input. Only the first two are used.")
+                .validator(|s| match usize::from_str(&s) {
+                    Ok(c) if c >= 1 => Ok(()),
+                    _ => Err("Channels should be a positive integer".to_string())
+                })
+        )
+        .arg(
+            clap::Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .value_name("HZ")
+                .default_value("48000")
+                .help("Sample rate of --pcm input and generated audio.")
+                .validator(|s| match usize::from_str(&s) {
+                    Ok(r) if r >= 1024 => Ok(()),
+                    _ => Err("Rate should be an integer of at least 1024".to_string())
+                })
+        )
+        .arg(
+            clap::Arg::with_name("midi")
+                .long("midi")
+                .conflicts_with_all(&["wav", "signal", "pcm", "pitch", "calibrate_noise", "calibrate_response"])
+                .help("Show the notes from a JACK MIDI input on the MIDI visualizer instead of the audio.")
+        )
+        .arg(
+            clap::Arg::with_name("pitch")
+                .long("pitch")
+                .help("Follow the pitch of a single voice or instrument and show it on the MIDI visualizer.")
+        )
+        .arg(
+            clap::Arg::with_name("fast")
+                .long("fast")
+                .requires("wav")
+                .help("Process the WAV file as fast as possible instead of in real time.")
+        )
+        .get_matches();
+    let low = f32::from_str(args.value_of("band_low").unwrap()).unwrap();
+    let high = f32::from_str(args.value_of("band_high").unwrap()).unwrap();
+    if high <= low {
+        clap::Error::value_validation_auto("The band range should have --band-low below --band-high".to_string()).exit();
+    }
+    // too many bands can't all be told apart, checked at the usual 48kHz
+    if let Some(n) = args.value_of("bands") {
+        let max = led::max_bands(low, high, 48000);
+        if usize::from_str(n).unwrap() > max {
+            clap::Error::value_validation_auto(format!("At most {} bands between {}Hz and {}Hz can be told apart", max, low, high)).exit();
+        }
+    }
+    args
+}
+fn validate_ms(s: String) -> Result<(), String> {
+    match f32::from_str(&s) {
+        Ok(f) if f >= 0.0 && f.is_finite() => Ok(()),
+        _ => Err("Time should be a non-negative number of milliseconds".to_string())
+    }
+}
+fn validate_hz(s: String) -> Result<(), String> {
+    match f32::from_str(&s) {
+        Ok(f) if f > 0.0 && f.is_finite() => Ok(()),
+        _ => Err("Frequency should be a positive number of Hz".to_string())
+    }
+}
+fn parse_color(s: &str) -> Result<[u8; 4], String> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 || !s.is_ascii() {
+        return Err("Color should be a hex triplet like ff8000".to_string());
+    }
+    let mut ret = [0; 4];
+    for i in 0..3 {
+        ret[i] = match u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
+            Ok(v) => v,
+            Err(_) => return Err("Color should be a hex triplet like ff8000".to_string())
+        };
+    }
+    Ok(ret)
 }
@@ -1,6 +1,8 @@
-use crate::audio::{Notifications,SpectrumError};
+use crate::audio::{self,Notifications,SpectrumError};
 use jack::{AsyncClient,Client,Control,MidiIn,Port,ProcessHandler,RawMidi};
 use std::sync::mpsc::{sync_channel,Receiver,SyncSender,TryRecvError};
+use std::thread;
+use std::time::Instant;
 
 pub struct MidiActive {
     recv: Receiver<MidiMessage>,
@@ -142,3 +144,145 @@ pub fn new_jack() -> Result<Client, SpectrumError> {
         }
     }
 
+/// Turns the pitch of a single voice or instrument from an audio source into
+/// MIDI notes, so the MIDI visualizer can follow it.
+pub struct PitchSource<S: audio::InactiveSource> {
+    producer: audio::PendingProducer<S>,
+    min_freq: f32,
+    max_freq: f32
+}
+impl<S: audio::InactiveSource> PitchSource<S> {
+    pub fn new(producer: audio::PendingProducer<S>) -> Self {
+        PitchSource { producer, min_freq: 80.0, max_freq: 2000.0 }
+    }
+    /// Range of fundamentals in Hz to look for, 80 to 2000 by default.
+    pub fn set_range(mut self, min_freq: f32, max_freq: f32) -> Self {
+        self.min_freq = min_freq;
+        self.max_freq = max_freq;
+        self
+    }
+}
+impl<S: audio::InactiveSource + 'static> InactiveSource for PitchSource<S> {
+    type ActiveType = PitchActive;
+    fn activate(self) -> Result<Self::ActiveType, SpectrumError> {
+        let mut tracker = audio::PitchTracker::new(self.producer.sample_rate(), self.min_freq, self.max_freq);
+        let active = self.producer.activate()?;
+        // low voices don't fit twice in a sample, so track a longer history
+        let hop = active.hop();
+        let mut history = audio::History::new(tracker.history_len().max(active.sample_size()));
+        let start = Instant::now();
+        let (sender, recv) = sync_channel(64);
+        thread::spawn(move || {
+            let mut follower = NoteFollower::new();
+            let mut events = Vec::new();
+            for ss in active {
+                follower.update(ss.history_pitch(&mut history, hop, &mut tracker), &mut events);
+                let time = start.elapsed().as_micros() as u64;
+                for event in events.drain(..) {
+                    if sender.send(MidiMessage { time, event }).is_err() {
+                        return; // deactivated
+                    }
+                }
+            }
+        });
+        Ok(PitchActive { recv, start })
+    }
+}
+
+pub struct PitchActive {
+    recv: Receiver<MidiMessage>,
+    start: Instant
+}
+impl ActiveSource for PitchActive {
+    type InactiveType = ();
+    fn deactivate(self) -> Result<Self::InactiveType, SpectrumError> {
+        Ok(()) // dropping the receiver stops the tracking thread and the audio source
+    }
+    fn cur_time(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+}
+impl Iterator for PitchActive {
+    type Item = MidiMessage;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.recv.try_recv() {
+            Ok(ok) => Some(ok),
+            Err(TryRecvError::Disconnected) => Some(MidiMessage {
+                event: MidiEvent::Unrecongized,
+                time: 0,
+            }),
+            Err(TryRecvError::Empty) => None
+        }
+    }
+}
+
+// A new note, or silence, has to hold for this many samples before the 
+// sounding note changes, so vibrato and noisy attacks don't chatter.
+const NOTE_HOLD: usize = 2;
+
+struct NoteFollower {
+    note: Option<u8>,
+    candidate: Option<(u8, u8)>, // note and velocity
+    count: usize
+}
+impl NoteFollower {
+    fn new() -> Self {
+        NoteFollower { note: None, candidate: None, count: 0 }
+    }
+    fn update(&mut self, pitch: Option<audio::Pitch>, events: &mut Vec<MidiEvent>) {
+        let heard = pitch.and_then(|p| {
+            let note = (69.0 + 12.0 * (p.freq / 440.0).log2()).round();
+            if !(0.0..=127.0).contains(&note) {
+                return None;
+            }
+            // -60dB to full scale over the velocity range
+            let vel = ((p.level + 60.0) / 60.0 * 126.0 + 1.0).clamp(1.0, 127.0);
+            Some((note as u8, vel as u8))
+        });
+        if heard.map(|h| h.0) == self.note {
+            self.count = 0;
+            return;
+        }
+        if heard.map(|h| h.0) == self.candidate.map(|c| c.0) {
+            self.count += 1;
+        } else {
+            self.count = 1;
+        }
+        self.candidate = heard;
+        if self.count >= NOTE_HOLD {
+            if let Some(note) = self.note {
+                events.push(MidiEvent::NoteOff(note, 0));
+            }
+            if let Some((note, vel)) = heard {
+                events.push(MidiEvent::NoteOn(note, vel));
+            }
+            self.note = heard.map(|h| h.0);
+            self.count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_note_follower() {
+        let mut follower = NoteFollower::new();
+        let mut events = Vec::new();
+        let a4 = Some(audio::Pitch { freq: 442.0, clarity: 0.9, level: 0.0 });
+        let b4 = Some(audio::Pitch { freq: 493.9, clarity: 0.9, level: -30.0 });
+        let mut notes = Vec::new();
+        for pitch in [a4, a4, a4, b4, a4, a4, b4, b4, None, None].iter() {
+            follower.update(*pitch, &mut events);
+            notes.push(events.drain(..).map(|e| match e {
+                MidiEvent::NoteOn(n, v) => (true, n, v),
+                MidiEvent::NoteOff(n, _) => (false, n, 0),
+                _ => panic!("unexpected event")
+            }).collect::<Vec<_>>());
+        }
+        // a single stray b4 is ignored, two in a row take over
+        assert_eq!(notes, vec![vec![], vec![(true, 69, 127)], vec![], vec![], vec![], vec![], vec![], 
+            vec![(false, 69, 0), (true, 71, 64)], vec![], vec![(false, 71, 0)]]);
+    }
+}
@@ -163,32 +163,11 @@ impl Note {
         } else { // we are fading in
             (time_past, fade_in * 128 * 256)
         };            
-        let mut base = [0; 4];
+        let mut color = [0; 4];
         for i in 0..3 {
-            base[i] = self.color.0[i] as u64 * power * self.vel as u64;
-        }
-        let center = self.note as usize * 2;
-        for i in 0..8 {
-            // calculate the final color based on the distance from the center led
-            let mut color = [0; 4];
-            for j in 0..3 {
-                color[j] = ((base[j] << (8 - i)) / div) as u8;
-            } 
-            
-            // actually set the led on either side of the center led
-            if let Some(first) = center.checked_sub(i) {
-                for j in 0..3 {
-                    leds[first][j] = leds[first][j].saturating_add(color[j]);
-                }
-            }
-            if i == 0 { continue; } // this is duplicative on the zero iteration.
-            let last = center + i;
-            if last < leds.len() {
-                for j in 0..3 {
-                    leds[last][j] = leds[last][j].saturating_add(color[j]);
-                }
-            }
+            color[i] = ((self.color.0[i] as u64 * power * self.vel as u64) << 8) / div;
         }
+        draw_key(self.note, [color[0] as u8, color[1] as u8, color[2] as u8, 0], leds);
         true 
     }
     fn set_off_time<T: LedConfig>(&mut self, off_time: u64, config: &T) -> bool {
@@ -315,7 +294,88 @@ impl<T, U, V> MidiRenderer<T> for MidiCon
     }
 }
 
+/// Adds `color` to the leds of a key, centred on led `note * 2` and halving in 
+/// brightness with each led away from the centre.
+pub(crate) fn draw_key(note: u8, color: [u8; 4], leds: &mut [[u8; 4]]) {
+    let center = note as usize * 2;
+    for i in 0..8 {
+        // calculate the final color based on the distance from the center led
+        let mut c = [0; 3];
+        for j in 0..3 {
+            c[j] = color[j] >> i;
+        }
+        
+        // actually set the led on either side of the center led
+        if let Some(first) = center.checked_sub(i) {
+            if first < leds.len() {
+                for j in 0..3 {
+                    leds[first][j] = leds[first][j].saturating_add(c[j]);
+                }
+            }
+        }
+        if i == 0 { continue; } // this is duplicative on the zero iteration.
+        let last = center + i;
+        if last < leds.len() {
+            for j in 0..3 {
+                leds[last][j] = leds[last][j].saturating_add(c[j]);
+            }
+        }
+    }
+}
+
 pub fn abs_diff_u(x: usize, y: usize) -> usize {
     if x > y { x - y } else { y - x }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // calc_leds as it was before drawing moved to draw_key
+    fn calc_leds_before(note: &Note, config: &LedConfigData, time_past: u64, leds: &mut [[u8; 4]]) {
+        let (fade_out, fade_in) = (config.get_fade_out() as u64, config.get_fade_in() as u64);
+        let total = fade_out + fade_in;
+        let (power, div) = if time_past > fade_in {
+            (total - time_past, fade_out * 128 * 256)
+        } else {
+            (time_past, fade_in * 128 * 256)
+        };
+        let base: Vec<u64> = note.color.0.iter().map(|c| *c as u64 * power * note.vel as u64).collect();
+        let center = note.note as usize * 2;
+        for i in 0..8 {
+            let color: Vec<u8> = base.iter().map(|b| ((b << (8 - i)) / div) as u8).collect();
+            if let Some(first) = center.checked_sub(i) {
+                for j in 0..3 {
+                    leds[first][j] = leds[first][j].saturating_add(color[j]);
+                }
+            }
+            if i == 0 { continue; }
+            let last = center + i;
+            if last < leds.len() {
+                for j in 0..3 {
+                    leds[last][j] = leds[last][j].saturating_add(color[j]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_calc_leds_unchanged() {
+        let config = LedConfigData::default();
+        let total = (config.fade_in + config.fade_out) as u64;
+        for &n in [0, 3, 60, 127].iter() {
+            for vel in (0..128).step_by(7).chain(Some(127)) {
+                for &color in [[255, 255, 255, 0], [255, 128, 1, 0], [17, 200, 99, 0]].iter() {
+                    let note = Note { on: true, note: n, vel, on_time: 0, off_time: 0, so_time: 0, color: Color(color) };
+                    for t in (0..total).step_by(24_989).chain(Some(config.fade_in as u64)) {
+                        let (mut after, mut before) = ([[0; 4]; 256], [[0; 4]; 256]);
+                        assert!(note.calc_leds(&config, t, &mut after));
+                        calc_leds_before(&note, &config, t, &mut before);
+                        assert_eq!(&after[..], &before[..], "note {} vel {} color {:?} at {}", n, vel, color, t);
+                    }
+                }
+            }
+        }
+    }
+}